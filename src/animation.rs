@@ -0,0 +1,289 @@
+// Node transforms over time, driven by the translation, rotation and scale channels of glTF animations.
+
+use gltf::{
+    Document,
+    buffer::Data,
+    animation::{Interpolation, Property, util::ReadOutputs}
+};
+use glam::{Vec3, Vec4, Quat, Affine3A};
+
+// Transforms sampled over an interval, between which moving nodes are interpolated.
+const MOTION_STEPS: usize = 16;
+
+#[derive(Debug)]
+pub struct Animation {
+    nodes: Vec<AnimatedNode>,
+    // Time of the last keyframe of any channel.
+    duration: f32
+}
+
+#[derive(Debug)]
+struct AnimatedNode {
+    parent: Option<usize>,
+    // Transform of the node when its channels do not override it.
+    rest: Trs,
+    translation: Option<Channel<Vec3>>,
+    rotation: Option<Channel<Quat>>,
+    scale: Option<Channel<Vec3>>
+}
+
+#[derive(Debug)]
+struct Channel<T> {
+    times: Vec<f32>,
+    // For cubic splines, the in-tangent, value and out-tangent of each keyframe.
+    values: Vec<T>,
+    interpolation: Interpolation
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Trs {
+    translation: Vec3,
+    rotation: Quat,
+    scale: Vec3
+}
+
+// World transform of a node sampled over the shutter interval.
+#[derive(Debug)]
+pub struct Motion {
+    start: f32,
+    end: f32,
+    steps: Vec<Trs>,
+    // Inverse of the transform at the start, which static geometry is posed at.
+    inverse_start: Affine3A
+}
+
+trait Interpolate: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+    // Cubic Hermite spline between two keyframes `delta` seconds apart, with tangents per second.
+    fn hermite(p0: Self, m0: Self, p1: Self, m1: Self, t: f32, delta: f32) -> Self;
+}
+
+impl Animation {
+    pub fn new(gltf: &Document, buffers: &[Data]) -> Self {
+        let mut nodes: Vec<AnimatedNode> = gltf
+            .nodes()
+            .map(|node| {
+                let (translation, rotation, scale) = node.transform().decomposed();
+
+                AnimatedNode {
+                    parent: None,
+                    rest: Trs {
+                        translation: translation.into(),
+                        rotation: Quat::from_array(rotation),
+                        scale: scale.into()
+                    },
+                    translation: None,
+                    rotation: None,
+                    scale: None
+                }
+            })
+            .collect();
+
+        for node in gltf.nodes() {
+            for child in node.children() {
+                nodes[child.index()].parent = Some(node.index());
+            }
+        }
+
+        let mut duration: f32 = 0.0;
+
+        for channel in gltf.animations().flat_map(|animation| animation.channels()) {
+            let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+            let (Some(inputs), Some(outputs)) = (reader.read_inputs(), reader.read_outputs()) else {
+                continue;
+            };
+
+            let times: Vec<f32> = inputs.collect();
+            if times.is_empty() {
+                continue;
+            }
+            duration = duration.max(times[times.len() - 1]);
+
+            let interpolation = channel.sampler().interpolation();
+            let node = &mut nodes[channel.target().node().index()];
+
+            match (channel.target().property(), outputs) {
+                (Property::Translation, ReadOutputs::Translations(values)) => node.translation = Some(Channel {
+                    times,
+                    values: values.map(Vec3::from).collect(),
+                    interpolation
+                }),
+                (Property::Rotation, ReadOutputs::Rotations(values)) => node.rotation = Some(Channel {
+                    times,
+                    values: values.into_f32().map(Quat::from_array).collect(),
+                    interpolation
+                }),
+                (Property::Scale, ReadOutputs::Scales(values)) => node.scale = Some(Channel {
+                    times,
+                    values: values.map(Vec3::from).collect(),
+                    interpolation
+                }),
+                // Morph targets deform meshes, which is not supported.
+                _ => ()
+            }
+        }
+
+        Self { nodes, duration }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    pub fn world_transform(&self, node: usize, time: f32) -> Affine3A {
+        let local = self.nodes[node].local(time).to_affine();
+
+        match self.nodes[node].parent {
+            Some(parent) => self.world_transform(parent, time) * local,
+            None => local
+        }
+    }
+
+    // Motion of the node over the interval, or None if it stays in place.
+    pub fn motion(&self, node: usize, start: f32, end: f32) -> Option<Motion> {
+        if end <= start || !self.is_animated(node) {
+            return None;
+        }
+
+        let steps: Vec<Trs> = (0..=MOTION_STEPS)
+            .map(|i| start + (end - start) * (i as f32) / (MOTION_STEPS as f32))
+            .map(|time| Trs::from_affine(self.world_transform(node, time)))
+            .collect();
+
+        if steps.iter().all(|step| *step == steps[0]) {
+            return None;
+        }
+
+        Some(Motion {
+            start,
+            end,
+            inverse_start: steps[0].to_affine().inverse(),
+            steps
+        })
+    }
+
+    // Whether the node or one of its ancestors has channels.
+    fn is_animated(&self, node: usize) -> bool {
+        let animated = &self.nodes[node];
+
+        animated.translation.is_some()
+            || animated.rotation.is_some()
+            || animated.scale.is_some()
+            || animated.parent.is_some_and(|parent| self.is_animated(parent))
+    }
+}
+
+impl AnimatedNode {
+    fn local(&self, time: f32) -> Trs {
+        Trs {
+            translation: self.translation.as_ref().map_or(self.rest.translation, |c| c.sample(time)),
+            rotation: self.rotation.as_ref().map_or(self.rest.rotation, |c| c.sample(time).normalize()),
+            scale: self.scale.as_ref().map_or(self.rest.scale, |c| c.sample(time))
+        }
+    }
+}
+
+impl<T: Interpolate> Channel<T> {
+    // Value at the time, holding the first and last keyframes outside of the animated range.
+    fn sample(&self, time: f32) -> T {
+        let keyframe = |i: usize| match self.interpolation {
+            Interpolation::CubicSpline => self.values[3 * i + 1],
+            _ => self.values[i]
+        };
+
+        let last = self.times.len() - 1;
+        let next = self.times.partition_point(|&t| t <= time);
+        if next == 0 {
+            return keyframe(0);
+        }
+        if next > last {
+            return keyframe(last);
+        }
+
+        let previous = next - 1;
+        let delta = self.times[next] - self.times[previous];
+        let t = (time - self.times[previous]) / delta;
+
+        match self.interpolation {
+            Interpolation::Step => keyframe(previous),
+            Interpolation::Linear => keyframe(previous).lerp(keyframe(next), t),
+            Interpolation::CubicSpline => T::hermite(
+                keyframe(previous),
+                self.values[3 * previous + 2],
+                keyframe(next),
+                self.values[3 * next],
+                t,
+                delta
+            )
+        }
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        Vec3::lerp(self, other, t)
+    }
+
+    fn hermite(p0: Self, m0: Self, p1: Self, m1: Self, t: f32, delta: f32) -> Self {
+        let [h00, h10, h01, h11] = hermite_basis(t);
+        h00 * p0 + h10 * delta * m0 + h01 * p1 + h11 * delta * m1
+    }
+}
+
+impl Interpolate for Quat {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self.slerp(other, t)
+    }
+
+    // Interpolated componentwise, then normalized by the caller.
+    fn hermite(p0: Self, m0: Self, p1: Self, m1: Self, t: f32, delta: f32) -> Self {
+        let [h00, h10, h01, h11] = hermite_basis(t);
+        Quat::from_vec4(h00 * Vec4::from(p0) + h10 * delta * Vec4::from(m0) + h01 * Vec4::from(p1) + h11 * delta * Vec4::from(m1))
+    }
+}
+
+fn hermite_basis(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    [2.0 * t3 - 3.0 * t2 + 1.0, t3 - 2.0 * t2 + t, -2.0 * t3 + 3.0 * t2, t3 - t2]
+}
+
+impl Trs {
+    fn from_affine(transform: Affine3A) -> Self {
+        let (scale, rotation, translation) = transform.to_scale_rotation_translation();
+        Self { translation, rotation, scale }
+    }
+
+    fn to_affine(self) -> Affine3A {
+        Affine3A::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        Self {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t)
+        }
+    }
+}
+
+impl Motion {
+    pub fn at(&self, time: f32) -> Affine3A {
+        let position = ((time - self.start) / (self.end - self.start)).clamp(0.0, 1.0) * MOTION_STEPS as f32;
+        let step = (position as usize).min(MOTION_STEPS - 1);
+
+        self.steps[step].lerp(self.steps[step + 1], position - step as f32).to_affine()
+    }
+
+    // Transform from the pose at the start to the pose at the time.
+    pub fn relative(&self, time: f32) -> Affine3A {
+        self.at(time) * self.inverse_start
+    }
+
+    // Times within the interval, finer than the steps, at which the swept bounds of moving geometry are taken.
+    pub fn sweep_times(&self) -> impl Iterator<Item = f32> + '_ {
+        let count = 4 * MOTION_STEPS;
+        (0..=count).map(move |i| self.start + (self.end - self.start) * (i as f32) / (count as f32))
+    }
+}
@@ -1,9 +1,10 @@
 use crate::{
     primitive::{Aabb, Ray},
-    triangle::{Triangle, HitRecord}
+    triangle::{Triangle, HitRecord},
+    animation::Motion
 };
 
-use std::ops::Range;
+use std::{borrow::Cow, ops::Range};
 
 pub const ROOT_IDX: usize = 0;
 
@@ -14,6 +15,8 @@ const SPACES: usize = 10;
 pub struct Bvh {
     nodes: Vec<BvhNode>,
     pub triangles: Vec<Triangle>,
+    // Motions of moving triangles over the shutter interval.
+    motions: Vec<Motion>,
     pub nodes_used: usize
 }
 
@@ -26,16 +29,11 @@ struct BvhNode {
 }
 
 impl BvhNode {
-    fn new(first_tri: usize, tri_count: usize, triangles: &Vec<Triangle>) -> Self {
+    fn new(first_tri: usize, tri_count: usize, triangles: &[Triangle]) -> Self {
         let mut aabb = Aabb::MAX;
 
         for tri in &triangles[first_tri..first_tri+tri_count] {
-            aabb.minimum = aabb.minimum.min(tri.v1.position);
-            aabb.minimum = aabb.minimum.min(tri.v2.position);
-            aabb.minimum = aabb.minimum.min(tri.v3.position);
-            aabb.maximum = aabb.maximum.max(tri.v1.position);
-            aabb.maximum = aabb.maximum.max(tri.v2.position);
-            aabb.maximum = aabb.maximum.max(tri.v3.position);
+            aabb = aabb.union(tri.bounds);
         }
 
         Self {
@@ -54,7 +52,7 @@ impl BvhNode {
         self.first_tri..self.first_tri+self.tri_count
     }
 
-    fn evaluate_sah(&self, axis: usize, pos: f32, triangles: &Vec<Triangle>) -> f32 {
+    fn evaluate_sah(&self, axis: usize, pos: f32, triangles: &[Triangle]) -> f32 {
         let mut left_box = Aabb::MAX;
         let mut right_box = Aabb::MAX;
 
@@ -64,14 +62,10 @@ impl BvhNode {
         for tri in &triangles[self.first_tri..self.first_tri+self.tri_count] {
             if tri.centroid[axis] < pos {
                 left_count += 1;
-                left_box.grow(tri.v1.position);
-                left_box.grow(tri.v2.position);
-                left_box.grow(tri.v3.position);
+                left_box = left_box.union(tri.bounds);
             } else {
                 right_count += 1;
-                right_box.grow(tri.v1.position);
-                right_box.grow(tri.v2.position);
-                right_box.grow(tri.v3.position);
+                right_box = right_box.union(tri.bounds);
             }
         }
 
@@ -85,7 +79,7 @@ impl BvhNode {
 }
 
 impl Bvh {
-    pub fn new(triangles: Vec<Triangle>) -> Self {
+    pub fn new(triangles: Vec<Triangle>, motions: Vec<Motion>) -> Self {
         let root = BvhNode::new(0, triangles.len(), &triangles);
         let mut nodes = Vec::with_capacity(triangles.len() * 2 - 1);
         nodes.push(root);
@@ -93,6 +87,7 @@ impl Bvh {
         let mut bvh = Bvh {
             nodes,
             triangles,
+            motions,
             nodes_used: 1
         };
 
@@ -100,21 +95,43 @@ impl Bvh {
         bvh
     }
 
-    pub fn intersects(&self, ray: &Ray) -> Option<HitRecord> {
+    // Hits rejected by `accept` are ignored, which allows alpha testing during traversal.
+    pub fn intersects(&self, ray: &Ray, accept: impl Fn(&Triangle, f32) -> bool) -> Option<HitRecord> {
+        self.nearest(ray, f32::INFINITY, accept)
+            .map(|(tri_idx, dist)| self.triangle_at(tri_idx, ray.time).create_record(ray, dist, tri_idx))
+    }
+
+    // The triangle in its pose at the time.
+    pub fn triangle_at(&self, index: usize, time: f32) -> Cow<'_, Triangle> {
+        let triangle = &self.triangles[index];
+
+        match triangle.motion {
+            Some(motion) => Cow::Owned(triangle.transformed(self.motions[motion].relative(time))),
+            None => Cow::Borrowed(triangle)
+        }
+    }
+
+    // Whether any accepted triangle is hit closer than the given distance.
+    pub fn occluded(&self, ray: &Ray, distance: f32, accept: impl Fn(&Triangle, f32) -> bool) -> bool {
+        self.nearest(ray, distance, accept).is_some()
+    }
+
+    fn nearest(&self, ray: &Ray, max_dist: f32, accept: impl Fn(&Triangle, f32) -> bool) -> Option<(usize, f32)> {
         let mut node = &self.nodes[ROOT_IDX];
         let mut stack = [node; 64];
         let mut stack_pointer = 0;
 
-        let mut nearest_dist = f32::INFINITY;
-        let mut nearest_tri: Option<&Triangle> = None;
+        let mut nearest_dist = max_dist;
+        let mut nearest_tri: Option<usize> = None;
 
         loop {
             if node.is_leaf() {
-                for tri in &self.triangles[node.tri_range()] {
+                for tri_idx in node.tri_range() {
+                    let tri = self.triangle_at(tri_idx, ray.time);
                     match tri.hit(ray) {
-                        Some(dist) if dist < nearest_dist => {
+                        Some(dist) if dist < nearest_dist && accept(&tri, dist) => {
                             nearest_dist = dist;
-                            nearest_tri = Some(tri);
+                            nearest_tri = Some(tri_idx);
                         },
                         _ => ()
                     }
@@ -157,7 +174,7 @@ impl Bvh {
             }
         }
 
-        nearest_tri.map(|tri| tri.create_record(ray, nearest_dist))
+        nearest_tri.map(|tri| (tri, nearest_dist))
     }
 
     fn subdivide(&mut self, node_idx: usize) {
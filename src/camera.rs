@@ -1,46 +1,210 @@
 use crate::{
-    IMAGE_WIDTH, IMAGE_HEIGHT,
-    primitive::Ray,
+    primitive::{Ray, RayDifferentials},
+    util::random_in_unit_disk,
+    animation::Motion,
+    RenderSettings
 };
 
-use glam::{Vec3A, Affine3A};
+use std::f32::consts::{PI, TAU};
+use glam::{Vec2, Vec3A, Affine3A};
 use fastrand::f32;
 
 #[derive(Debug)]
 pub struct Camera {
+    pub name: String,
     half_width: f32,
     half_height: f32,
-    meter_per_pixel: f32,
-    focal_length: f32,
-    transform: Affine3A
+    // Size of a pixel on the image plane, or its angular size for panoramic projections.
+    pixel_size: Vec2,
+    projection: Projection,
+    // Pixel offset of the ray differentials, shrinking with the sample count.
+    differential_scale: f32,
+    // Interval over which the shutter is open, in seconds of animation time.
+    shutter_open: f32,
+    shutter_close: f32,
+    transform: Affine3A,
+    // Movement of the camera node while the shutter is open, replacing the fixed transform.
+    motion: Option<Motion>
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Projection {
+    // Rays through a thin lens, towards an image plane `focal_length` away.
+    Perspective { focal_length: f32, lens: Lens },
+    // Parallel rays along the view axis, starting on the image plane.
+    Orthographic,
+    // Longitude and latitude of the full sphere, centered on the view axis.
+    Equirectangular,
+    // Angle from the view axis proportional to the distance from the image center, up to `max_angle`.
+    Fisheye { max_angle: f32 }
+}
+
+// Thin lens focused on a plane at a distance along the view axis. A zero radius gives a pinhole camera.
+#[derive(Debug, Clone, Copy)]
+pub struct Lens {
+    pub radius: f32,
+    pub focus_distance: f32
+}
+
+// Width of a full-frame sensor in meters, which Blender also uses by default.
+const SENSOR_WIDTH: f32 = 0.036;
+
+impl Lens {
+    pub const PINHOLE: Self = Self { radius: 0.0, focus_distance: f32::INFINITY };
+
+    // Aperture radius of a full-frame camera with the same field of view along the wider image side.
+    pub fn aperture_radius(f_stop: f32, y_fov: f32, aspect_ratio: f32) -> f32 {
+        let tan_half_fov = (y_fov / 2.0).tan() * aspect_ratio.max(1.0);
+        let focal_length = (SENSOR_WIDTH / 2.0) / tan_half_fov;
+
+        focal_length / (2.0 * f_stop)
+    }
 }
 
 impl Camera {
-    pub fn new(aspect_ratio: f32, y_fov: f32, transform: Affine3A) -> Self {
+    // Image plane with a height of 1 / aspect ratio.
+    pub fn perspective(name: String, aspect_ratio: f32, y_fov: f32, lens: Lens, transform: Affine3A, settings: &RenderSettings) -> Self {
         let h = aspect_ratio.recip();
 
-        let pixel_height = IMAGE_HEIGHT as f32;
-        let pixel_width = IMAGE_WIDTH as f32;
+        Self::new(
+            name,
+            Projection::Perspective { focal_length: (h / 2.0) / f32::tan(y_fov / 2.0), lens },
+            Vec2::splat(h / settings.height as f32),
+            transform,
+            settings
+        )
+    }
+
+    // View of 2 * xmag by 2 * ymag scene units, as defined by glTF.
+    pub fn orthographic(name: String, xmag: f32, ymag: f32, transform: Affine3A, settings: &RenderSettings) -> Self {
+        Self::new(
+            name,
+            Projection::Orthographic,
+            Vec2::new(2.0 * xmag / settings.width as f32, 2.0 * ymag / settings.height as f32),
+            transform,
+            settings
+        )
+    }
+
+    // Full sphere with 360° across the width and 180° across the height, best viewed at an aspect ratio of 2.
+    pub fn equirectangular(name: String, transform: Affine3A, settings: &RenderSettings) -> Self {
+        Self::new(
+            name,
+            Projection::Equirectangular,
+            Vec2::new(TAU / settings.width as f32, PI / settings.height as f32),
+            transform,
+            settings
+        )
+    }
+
+    // Equidistant fisheye whose image circle spans the shorter image side.
+    pub fn fisheye(name: String, fov: f32, transform: Affine3A, settings: &RenderSettings) -> Self {
+        Self::new(
+            name,
+            Projection::Fisheye { max_angle: fov / 2.0 },
+            Vec2::splat(fov / settings.width.min(settings.height) as f32),
+            transform,
+            settings
+        )
+    }
 
+    fn new(name: String, projection: Projection, pixel_size: Vec2, transform: Affine3A, settings: &RenderSettings) -> Self {
         Camera {
-            half_width: pixel_width / 2.0,
-            half_height: pixel_height / 2.0,
-            meter_per_pixel: h / pixel_height,
-            focal_length: (h / 2.0) / f32::tan(y_fov / 2.0),
+            name,
+            half_width: settings.width as f32 / 2.0,
+            half_height: settings.height as f32 / 2.0,
+            pixel_size,
+            projection,
+            differential_scale: (settings.samples as f32).sqrt().recip().max(0.125),
+            shutter_open: settings.time,
+            shutter_close: settings.time + settings.shutter,
             transform,
+            motion: None
         }
     }
 
-    pub fn ray_from(&self, x: usize, y: usize) -> Ray {
+    pub fn with_motion(self, motion: Option<Motion>) -> Self {
+        Self { motion, ..self }
+    }
+
+    // Ray through a random point of the pixel, or None outside the image circle of a fisheye.
+    pub fn ray_from(&self, x: usize, y: usize) -> Option<Ray> {
         let x_offset = f32() - 0.5;
         let y_offset = f32() - 0.5;
 
-        let plane_x = ((x as f32) + x_offset - self.half_width) * self.meter_per_pixel;
-        let plane_y = (self.half_height - (y as f32) + y_offset) * self.meter_per_pixel;
+        let plane_x = ((x as f32) + x_offset - self.half_width) * self.pixel_size.x;
+        let plane_y = (self.half_height - (y as f32) + y_offset) * self.pixel_size.y;
+        let offset = self.pixel_size * self.differential_scale;
 
-        Ray::new(
-            self.transform.translation,
-            self.transform.transform_vector3a(Vec3A::new(plane_x, plane_y, -self.focal_length).normalize())
-        )
+        let time = self.shutter_open + (self.shutter_close - self.shutter_open) * f32();
+        let transform = self.motion.as_ref().map_or(self.transform, |motion| motion.at(time));
+
+        match self.projection {
+            Projection::Perspective { focal_length, lens } => {
+                let lens_point = random_in_unit_disk() * lens.radius;
+                let origin = transform.transform_point3a(lens_point);
+
+                // From the lens point towards where the pinhole ray meets the plane in focus.
+                let direction = |x: f32, y: f32| {
+                    let pinhole = Vec3A::new(x, y, -focal_length).normalize();
+                    let through_lens = match lens.focus_distance.is_finite() {
+                        true => (pinhole * (lens.focus_distance / -pinhole.z) - lens_point).normalize(),
+                        false => pinhole
+                    };
+
+                    transform.transform_vector3a(through_lens)
+                };
+
+                Some(Ray::new(origin, direction(plane_x, plane_y)).with_time(time).with_differentials(Some(RayDifferentials {
+                    rx_origin: origin,
+                    rx_direction: direction(plane_x + offset.x, plane_y),
+                    ry_origin: origin,
+                    ry_direction: direction(plane_x, plane_y - offset.y)
+                })))
+            },
+            Projection::Orthographic => {
+                let origin = |x: f32, y: f32| transform.transform_point3a(Vec3A::new(x, y, 0.0));
+                let direction = transform.transform_vector3a(Vec3A::NEG_Z).normalize();
+
+                Some(Ray::new(origin(plane_x, plane_y), direction).with_time(time).with_differentials(Some(RayDifferentials {
+                    rx_origin: origin(plane_x + offset.x, plane_y),
+                    rx_direction: direction,
+                    ry_origin: origin(plane_x, plane_y - offset.y),
+                    ry_direction: direction
+                })))
+            },
+            Projection::Equirectangular | Projection::Fisheye { .. } => {
+                if let Projection::Fisheye { max_angle } = self.projection && plane_x.hypot(plane_y) > max_angle {
+                    return None;
+                }
+
+                let origin = transform.translation;
+                let direction = |x: f32, y: f32| transform.transform_vector3a(self.panoramic_direction(x, y));
+
+                Some(Ray::new(origin, direction(plane_x, plane_y)).with_time(time).with_differentials(Some(RayDifferentials {
+                    rx_origin: origin,
+                    rx_direction: direction(plane_x + offset.x, plane_y),
+                    ry_origin: origin,
+                    ry_direction: direction(plane_x, plane_y - offset.y)
+                })))
+            }
+        }
+    }
+
+    // Camera space direction of the angular image plane coordinates.
+    fn panoramic_direction(&self, x: f32, y: f32) -> Vec3A {
+        match self.projection {
+            // Longitude from -z towards +x, matching equirectangular environment maps.
+            Projection::Equirectangular => Vec3A::new(y.cos() * x.sin(), y.sin(), -y.cos() * x.cos()),
+            _ => {
+                let angle = x.hypot(y);
+                if angle == 0.0 {
+                    return Vec3A::NEG_Z;
+                }
+
+                let scale = angle.sin() / angle;
+                Vec3A::new(x * scale, y * scale, -angle.cos())
+            }
+        }
     }
 }
@@ -0,0 +1,245 @@
+use std::{
+    fmt::Display,
+    ops::RangeInclusive,
+    path::PathBuf,
+    str::FromStr
+};
+
+use raytracer::{RenderSettings, CameraSelection, Panorama, EnvironmentSource};
+
+pub const USAGE: &str = "\
+Usage: raytracer [OPTIONS] [SCENE]
+
+Renders a glTF binary (.glb) scene. Without SCENE, the only scene in scenes/ is rendered.
+
+Options:
+  -o, --output <PATH>        Output png path [default: out/<scene>.png]
+  -r, --resolution <WxH>     Image resolution, e.g. 1920x1080
+      --width <PIXELS>       Image width
+      --height <PIXELS>      Image height
+  -s, --samples <N>          Samples per pixel
+  -b, --bounces <N>          Maximum ray bounces
+      --rr-depth <N>         Bounces before Russian roulette may terminate a path
+  -c, --camera <INDEX|NAME>  Index or name of the camera to render from [default: 0]
+      --all-cameras          Render every camera to its own image, named <output>_<camera>.png
+      --aperture <RADIUS>    Lens aperture radius in scene units, for depth of field
+      --f-stop <N>           Lens aperture as an f-number of a full-frame camera, for depth of field
+      --focus-distance <D>   Distance to the plane in focus [default: surface at the image center]
+      --panorama <TYPE>      Render panoramas instead, 'equirectangular' (360°) or 'fisheye'
+      --fisheye-fov <DEG>    Field of view of fisheye panoramas [default: 180]
+      --bicubic              Filter textures bicubically instead of bilinearly
+      --time <SECONDS>       Animation time to render at [default: 0]
+      --shutter <SECONDS>    Time the shutter stays open, blurring animated objects and cameras [default: 0]
+      --animation            Render every frame of the animation, named <output>_0001.png and onwards
+      --frames <FIRST-LAST>  Render only these frames of the animation, counted from 1
+      --fps <N>              Frames per second of the animation [default: 24]
+  -e, --environment <ENV>    Equirectangular .hdr image, constant color R,G,B, 'gradient' or 'sky' [default: gradient]
+      --env-rotation <DEG>   Rotation of the environment about the vertical axis
+      --env-intensity <X>    Multiplier for environment radiance
+      --sun-elevation <DEG>  Angle of the sun above the horizon for the sky environment [default: 45]
+      --sun-azimuth <DEG>    Angle of the sun from -z towards +x for the sky environment [default: 0]
+      --turbidity <T>        Haziness of the sky environment, from 2 (clear) to 10 (hazy) [default: 3]
+  -t, --threads <N>          Number of render threads [default: all cores]
+  -l, --list                 List scenes in scenes/, or the cameras of SCENE
+  -h, --help                 Print this help";
+
+pub struct Args {
+    pub scene: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub threads: Option<usize>,
+    // Renders the animation as an image sequence, optionally limited to a range of frames.
+    pub animation: bool,
+    pub frames: Option<RangeInclusive<usize>>,
+    pub fps: f32,
+    pub list: bool,
+    pub help: bool,
+    pub settings: RenderSettings
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args {
+            scene: None,
+            output: None,
+            threads: None,
+            animation: false,
+            frames: None,
+            fps: 24.0,
+            list: false,
+            help: false,
+            settings: RenderSettings::default()
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", name));
+
+            match arg.as_str() {
+                "-o" | "--output" => parsed.output = Some(value(&arg)?.into()),
+                "-r" | "--resolution" => {
+                    let resolution = value(&arg)?;
+                    let (width, height) = resolution
+                        .split_once('x')
+                        .ok_or_else(|| format!("Invalid resolution '{}', expected WIDTHxHEIGHT", resolution))?;
+                    parsed.settings.width = parse_positive(&arg, width)?;
+                    parsed.settings.height = parse_positive(&arg, height)?;
+                },
+                "--width" => parsed.settings.width = parse_positive(&arg, &value(&arg)?)?,
+                "--height" => parsed.settings.height = parse_positive(&arg, &value(&arg)?)?,
+                "-s" | "--samples" => parsed.settings.samples = parse_positive(&arg, &value(&arg)?)?,
+                "-b" | "--bounces" => parsed.settings.bounces = parse_value(&arg, &value(&arg)?)?,
+                "--rr-depth" => parsed.settings.russian_roulette_depth = parse_value(&arg, &value(&arg)?)?,
+                "-c" | "--camera" => {
+                    let camera = value(&arg)?;
+                    parsed.settings.camera = match camera.parse() {
+                        Ok(index) => CameraSelection::Index(index),
+                        Err(_) => CameraSelection::Name(camera)
+                    };
+                },
+                "--all-cameras" => parsed.settings.camera = CameraSelection::All,
+                "--aperture" => parsed.settings.lens.aperture_radius = Some(parse_value(&arg, &value(&arg)?)?),
+                "--f-stop" => parsed.settings.lens.f_stop = Some(parse_value(&arg, &value(&arg)?)?),
+                "--focus-distance" => parsed.settings.lens.focus_distance = Some(parse_value(&arg, &value(&arg)?)?),
+                "--panorama" => parsed.settings.panorama = Some(parse_panorama(&arg, &value(&arg)?)?),
+                "--fisheye-fov" => parsed.settings.fisheye_fov = Some(parse_value(&arg, &value(&arg)?)?),
+                "--bicubic" => parsed.settings.bicubic_textures = true,
+                "--time" => parsed.settings.time = parse_value(&arg, &value(&arg)?)?,
+                "--shutter" => parsed.settings.shutter = parse_value(&arg, &value(&arg)?)?,
+                "-e" | "--environment" => parsed.settings.environment = parse_environment(&arg, &value(&arg)?)?,
+                "--env-rotation" => parsed.settings.environment_rotation = parse_value(&arg, &value(&arg)?)?,
+                "--env-intensity" => parsed.settings.environment_intensity = parse_value(&arg, &value(&arg)?)?,
+                "--sun-elevation" => parsed.settings.sky.sun_elevation = parse_value(&arg, &value(&arg)?)?,
+                "--sun-azimuth" => parsed.settings.sky.sun_azimuth = parse_value(&arg, &value(&arg)?)?,
+                "--turbidity" => parsed.settings.sky.turbidity = parse_value(&arg, &value(&arg)?)?,
+                "--animation" => parsed.animation = true,
+                "--frames" => {
+                    let frames = value(&arg)?;
+                    let (first, last) = frames
+                        .split_once('-')
+                        .ok_or_else(|| format!("Invalid frames '{}', expected FIRST-LAST", frames))?;
+                    let (first, last) = (parse_positive(&arg, first)?, parse_positive(&arg, last)?);
+                    if first > last {
+                        return Err(format!("Invalid frames '{}', the first frame comes after the last", frames));
+                    }
+                    parsed.frames = Some(first..=last);
+                    parsed.animation = true;
+                },
+                "--fps" => {
+                    parsed.fps = parse_value(&arg, &value(&arg)?)?;
+                    if !parsed.fps.is_finite() || parsed.fps <= 0.0 {
+                        return Err(format!("Value for {} must be a finite number greater than zero", arg));
+                    }
+                },
+                "-t" | "--threads" => parsed.threads = Some(parse_positive(&arg, &value(&arg)?)?),
+                "-l" | "--list" => parsed.list = true,
+                "-h" | "--help" => parsed.help = true,
+                _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'", arg)),
+                _ if parsed.scene.is_none() => parsed.scene = Some(arg.into()),
+                _ => return Err(format!("Unexpected argument '{}'", arg))
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value '{}' for {}: {}", value, name, e))
+}
+
+fn parse_positive(name: &str, value: &str) -> Result<usize, String> {
+    match parse_value(name, value)? {
+        0 => Err(format!("Value for {} must be greater than zero", name)),
+        v => Ok(v)
+    }
+}
+
+fn parse_panorama(name: &str, value: &str) -> Result<Panorama, String> {
+    Panorama::from_name(value)
+        .ok_or_else(|| format!("Invalid value '{}' for {}, expected 'equirectangular' or 'fisheye'", value, name))
+}
+
+fn parse_environment(name: &str, value: &str) -> Result<EnvironmentSource, String> {
+    match value {
+        "gradient" => return Ok(EnvironmentSource::Gradient),
+        "sky" => return Ok(EnvironmentSource::Sky),
+        _ => ()
+    }
+
+    let components: Vec<&str> = value.split(',').collect();
+    match components.as_slice() {
+        [r, g, b] => Ok(EnvironmentSource::Constant([
+            parse_value(name, r)?,
+            parse_value(name, g)?,
+            parse_value(name, b)?
+        ])),
+        // Commas only appear in colors, so a malformed color is not mistaken for a file path.
+        _ if value.contains(',') => Err(format!("Invalid value '{}' for {}, expected a color as 'R,G,B'", value, name)),
+        _ => Ok(EnvironmentSource::Image(value.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn frames_are_parsed_as_an_inclusive_range() {
+        let args = parse(&["--frames", "2-5"]).unwrap();
+
+        assert!(args.animation);
+        assert_eq!(args.frames, Some(2..=5));
+    }
+
+    #[test]
+    fn reversed_or_malformed_frames_are_rejected() {
+        assert!(parse(&["--frames", "3-1"]).is_err());
+        assert!(parse(&["--frames", "3"]).is_err());
+        assert!(parse(&["--frames", "0-2"]).is_err());
+    }
+
+    #[test]
+    fn fps_must_be_finite_and_positive() {
+        for fps in ["NaN", "inf", "0", "-24"] {
+            assert!(parse(&["--fps", fps]).is_err(), "{}", fps);
+        }
+
+        assert_eq!(parse(&["--fps", "30"]).unwrap().fps, 30.0);
+    }
+
+    #[test]
+    fn environment_colors_need_three_numbers() {
+        assert!(matches!(
+            parse(&["-e", "0.1,0.2,0.3"]).unwrap().settings.environment,
+            EnvironmentSource::Constant([0.1, 0.2, 0.3])
+        ));
+        assert!(parse(&["-e", "1,2"]).is_err());
+        assert!(parse(&["-e", "1,2,3,4"]).is_err());
+        assert!(parse(&["-e", "1,x,3"]).is_err());
+    }
+
+    #[test]
+    fn environment_paths_and_names_are_kept() {
+        assert!(matches!(parse(&["-e", "sky"]).unwrap().settings.environment, EnvironmentSource::Sky));
+        assert!(matches!(
+            parse(&["-e", "studio.hdr"]).unwrap().settings.environment,
+            EnvironmentSource::Image(path) if path.as_os_str() == "studio.hdr"
+        ));
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["--samples"]).is_err());
+    }
+}
@@ -0,0 +1,449 @@
+use crate::{
+    primitive::Color,
+    settings::EnvironmentSource,
+    light::LightSample,
+    sky::PhysicalSky,
+    RenderSettings
+};
+
+use std::{
+    f32::consts::{PI, TAU},
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path
+};
+use glam::{Quat, Vec2, Vec3A};
+use fastrand::f32;
+
+// The sky gradient used when no environment is configured.
+const GRADIENT_BOTTOM: Color = Color::WHITE;
+const GRADIENT_TOP: Color = Color::rgb(0.5, 0.7, 1.0);
+
+// Radiance arriving from infinitely far away, seen by rays leaving the scene.
+#[derive(Debug)]
+pub struct Environment {
+    source: Source,
+    // Maps world directions into the frame of the environment.
+    to_local: Quat,
+    intensity: f32
+}
+
+#[derive(Debug)]
+enum Source {
+    Constant(Color),
+    // Blends from straight down to straight up.
+    Gradient { bottom: Color, top: Color },
+    Map(EnvironmentMap),
+    Sky(PhysicalSky)
+}
+
+// Equirectangular image, with the image center facing -z and the top row facing +y.
+#[derive(Debug)]
+struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+    distribution: Distribution
+}
+
+// Piecewise constant distribution over the pixels of a map, proportional to luminance times the solid angle they cover.
+#[derive(Debug)]
+struct Distribution {
+    // Cumulative weights along each row.
+    row_cdfs: Vec<Vec<f32>>,
+    // Cumulative row totals.
+    marginal_cdf: Vec<f32>,
+    total: f32
+}
+
+impl Environment {
+    pub fn new(settings: &RenderSettings) -> std::result::Result<Self, String> {
+        let source = match &settings.environment {
+            EnvironmentSource::Gradient => Source::Gradient { bottom: GRADIENT_BOTTOM, top: GRADIENT_TOP },
+            EnvironmentSource::Constant([r, g, b]) => Source::Constant(Color::rgb(*r, *g, *b)),
+            EnvironmentSource::Image(path) => Source::Map(EnvironmentMap::load(path)
+                .map_err(|e| format!("Cannot load environment map '{}': {}", path.display(), e))?),
+            EnvironmentSource::Sky => Source::Sky(PhysicalSky::new(&settings.sky))
+        };
+
+        Ok(Self {
+            source,
+            to_local: Quat::from_rotation_y(-settings.environment_rotation.to_radians()),
+            intensity: settings.environment_intensity
+        })
+    }
+
+    // Direction towards a bright part of the environment, for image environments and the sun.
+    pub fn sample(&self) -> Option<LightSample> {
+        let (local, pdf) = match &self.source {
+            Source::Map(map) => map.sample()?,
+            Source::Sky(sky) => sky.sample_sun(),
+            _ => return None
+        };
+
+        let direction = self.to_local.inverse() * local;
+
+        Some(LightSample {
+            direction,
+            distance: f32::INFINITY,
+            radiance: self.radiance(direction),
+            pdf,
+            delta: false
+        })
+    }
+
+    // Solid angle density with which `sample` chooses the direction.
+    pub fn pdf(&self, direction: Vec3A) -> f32 {
+        match &self.source {
+            Source::Map(map) => map.pdf(self.to_local * direction),
+            Source::Sky(sky) => sky.pdf(self.to_local * direction),
+            _ => 0.0
+        }
+    }
+
+    pub fn radiance(&self, direction: Vec3A) -> Color {
+        let direction = self.to_local * direction;
+
+        let color = match &self.source {
+            Source::Constant(color) => *color,
+            Source::Gradient { bottom, top } => {
+                let a = 0.5 * (direction.y + 1.0);
+                (1.0 - a) * *bottom + a * *top
+            },
+            Source::Map(map) => map.lookup(direction),
+            Source::Sky(sky) => sky.radiance(direction)
+        };
+
+        color * self.intensity
+    }
+}
+
+impl EnvironmentMap {
+    // Reads a Radiance RGBE (.hdr) image.
+    fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        let mut reader = HdrReader { data: &data, position: 0 };
+
+        let magic = reader.line()?;
+        if magic != "#?RADIANCE" && magic != "#?RGBE" {
+            return Err(invalid("not a Radiance HDR file"));
+        }
+
+        loop {
+            let line = reader.line()?;
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some(format) = line.strip_prefix("FORMAT=") && format != "32-bit_rle_rgbe" {
+                return Err(invalid("only RGBE pixels are supported"));
+            }
+        }
+
+        // Only the standard top-to-bottom, left-to-right orientation is supported.
+        let resolution = reader.line()?;
+        let (height, width) = match resolution.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["-Y", height, "+X", width] => (
+                height.parse().map_err(|_| invalid("invalid height"))?,
+                width.parse().map_err(|_| invalid("invalid width"))?
+            ),
+            _ => return Err(invalid("unsupported image orientation"))
+        };
+
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut scanline = vec![[0u8; 4]; width];
+        for _ in 0..height {
+            reader.scanline(&mut scanline)?;
+            pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_color(rgbe)));
+        }
+
+        let distribution = Distribution::new(&pixels, width, height);
+        Ok(Self { width, height, pixels, distribution })
+    }
+
+    // Bilinear lookup, wrapping around horizontally.
+    fn lookup(&self, direction: Vec3A) -> Color {
+        let Vec2 { x: u, y: v } = direction_to_uv(direction);
+
+        let x = u * self.width as f32 - 0.5;
+        let y = (v * self.height as f32 - 0.5).clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let fetch = |x: isize, y: usize| {
+            let x = x.rem_euclid(self.width as isize) as usize;
+            self.pixels[y.min(self.height - 1) * self.width + x]
+        };
+
+        let (x0, y0) = (x0 as isize, y0 as usize);
+        let top = fetch(x0, y0) * (1.0 - fx) + fetch(x0 + 1, y0) * fx;
+        let bottom = fetch(x0, y0 + 1) * (1.0 - fx) + fetch(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    // Local direction and its solid angle density.
+    fn sample(&self) -> Option<(Vec3A, f32)> {
+        let (x, y) = self.distribution.sample()?;
+        let uv = Vec2::new(
+            (x as f32 + f32()) / self.width as f32,
+            (y as f32 + f32()) / self.height as f32
+        );
+
+        let pdf = self.pdf_at(x, y, uv.y);
+        (pdf > 0.0).then(|| (uv_to_direction(uv), pdf))
+    }
+
+    fn pdf(&self, direction: Vec3A) -> f32 {
+        let uv = direction_to_uv(direction);
+        let x = ((uv.x * self.width as f32) as usize).min(self.width - 1);
+        let y = ((uv.y * self.height as f32) as usize).min(self.height - 1);
+        self.pdf_at(x, y, uv.y)
+    }
+
+    // Density over the image converted to solid angle.
+    fn pdf_at(&self, x: usize, y: usize, v: f32) -> f32 {
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        let image_pdf = self.distribution.probability(x, y) * (self.width * self.height) as f32;
+        image_pdf / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl Distribution {
+    fn new(pixels: &[Color], width: usize, height: usize) -> Self {
+        let row_cdfs: Vec<Vec<f32>> = pixels
+            .chunks(width)
+            .enumerate()
+            .map(|(y, row)| {
+                // Rows near the poles cover less solid angle.
+                let sin_theta = ((y as f32 + 0.5) / height as f32 * PI).sin();
+                let mut sum = 0.0;
+                row
+                    .iter()
+                    .map(|color| {
+                        sum += color.luminance().max(0.0) * sin_theta;
+                        sum
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut total = 0.0;
+        let marginal_cdf = row_cdfs
+            .iter()
+            .map(|row| {
+                total += row[width - 1];
+                total
+            })
+            .collect();
+
+        Self {
+            row_cdfs,
+            marginal_cdf,
+            total
+        }
+    }
+
+    // Picks a pixel proportional to its weight.
+    fn sample(&self) -> Option<(usize, usize)> {
+        if self.total <= 0.0 {
+            return None;
+        }
+
+        let target = f32() * self.total;
+        let y = self.marginal_cdf.partition_point(|&c| c < target).min(self.marginal_cdf.len() - 1);
+
+        let row = &self.row_cdfs[y];
+        let target = f32() * row[row.len() - 1];
+        let x = row.partition_point(|&c| c < target).min(row.len() - 1);
+
+        Some((x, y))
+    }
+
+    fn probability(&self, x: usize, y: usize) -> f32 {
+        if self.total <= 0.0 {
+            return 0.0;
+        }
+
+        let row = &self.row_cdfs[y];
+        let weight = if x == 0 { row[0] } else { row[x] - row[x - 1] };
+        weight / self.total
+    }
+}
+
+// Equirectangular coordinates, u increasing with the angle from -z towards +x and v from +y downwards.
+fn direction_to_uv(direction: Vec3A) -> Vec2 {
+    Vec2::new(
+        0.5 + direction.x.atan2(-direction.z) / TAU,
+        direction.y.clamp(-1.0, 1.0).acos() / PI
+    )
+}
+
+fn uv_to_direction(uv: Vec2) -> Vec3A {
+    let phi = (uv.x - 0.5) * TAU;
+    let (sin_theta, cos_theta) = (uv.y * PI).sin_cos();
+    Vec3A::new(sin_theta * phi.sin(), cos_theta, -sin_theta * phi.cos())
+}
+
+struct HdrReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl HdrReader<'_> {
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self.data
+            .get(self.position)
+            .ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn line(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        loop {
+            match self.byte()? {
+                b'\n' => return String::from_utf8(line).map_err(|_| invalid("invalid header")),
+                byte => line.push(byte)
+            }
+        }
+    }
+
+    fn pixel(&mut self) -> Result<[u8; 4]> {
+        Ok([self.byte()?, self.byte()?, self.byte()?, self.byte()?])
+    }
+
+    fn scanline(&mut self, scanline: &mut [[u8; 4]]) -> Result<()> {
+        let width = scanline.len();
+        let first = self.pixel()?;
+
+        // Scanlines with run length encoded channels start with a 2, 2 marker followed by the width.
+        let encoded = (8..0x8000).contains(&width)
+            && first[0] == 2
+            && first[1] == 2
+            && (usize::from(first[2]) << 8 | usize::from(first[3])) == width;
+
+        if !encoded {
+            return self.flat_scanline(first, scanline);
+        }
+
+        for channel in 0..4 {
+            let mut x = 0;
+            while x < width {
+                let count = self.byte()?;
+                let (count, run) = match count > 128 {
+                    true => (usize::from(count - 128), true),
+                    false => (usize::from(count), false)
+                };
+
+                if count == 0 || x + count > width {
+                    return Err(invalid("corrupt run length encoding"));
+                }
+
+                let value = if run { self.byte()? } else { 0 };
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[channel] = if run { value } else { self.byte()? };
+                }
+
+                x += count;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Uncompressed pixels, possibly with the original format's repeat markers.
+    fn flat_scanline(&mut self, first: [u8; 4], scanline: &mut [[u8; 4]]) -> Result<()> {
+        let mut pixel = first;
+        let mut x = 0;
+        let mut shift = 0;
+
+        loop {
+            if pixel[..3] == [1, 1, 1] {
+                if x == 0 {
+                    return Err(invalid("repeat marker at scanline start"));
+                }
+
+                let count = usize::from(pixel[3]) << shift;
+                let previous = scanline[x - 1];
+                for _ in 0..count.min(scanline.len() - x) {
+                    scanline[x] = previous;
+                    x += 1;
+                }
+                shift += 8;
+            } else {
+                scanline[x] = pixel;
+                x += 1;
+                shift = 0;
+            }
+
+            if x == scanline.len() {
+                return Ok(());
+            }
+
+            pixel = self.pixel()?;
+        }
+    }
+}
+
+fn rgbe_to_color([r, g, b, e]: [u8; 4]) -> Color {
+    if e == 0 {
+        return Color::BLACK;
+    }
+
+    let scale = 2f32.powi(i32::from(e) - 136);
+    Color::rgb(r as f32 * scale, g as f32 * scale, b as f32 * scale)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIXELS: [[u8; 4]; 8] = [
+        [10, 20, 30, 128], [10, 20, 30, 128], [10, 20, 30, 128], [1, 2, 3, 130],
+        [4, 5, 6, 130], [4, 5, 6, 130], [4, 5, 6, 130], [4, 5, 6, 130]
+    ];
+
+    fn read_scanline(data: &[u8]) -> Result<Vec<[u8; 4]>> {
+        let mut reader = HdrReader { data, position: 0 };
+        let mut scanline = vec![[0u8; 4]; PIXELS.len()];
+        reader.scanline(&mut scanline)?;
+        Ok(scanline)
+    }
+
+    #[test]
+    fn flat_scanline_is_read_pixel_by_pixel() {
+        let data: Vec<u8> = PIXELS.iter().flatten().copied().collect();
+        assert_eq!(read_scanline(&data).unwrap(), PIXELS);
+    }
+
+    #[test]
+    fn flat_scanline_repeats_the_previous_pixel() {
+        let data = [[10, 20, 30, 128], [1, 1, 1, 2], [1, 2, 3, 130], [4, 5, 6, 130], [1, 1, 1, 3]].concat();
+        assert_eq!(read_scanline(&data).unwrap(), PIXELS);
+    }
+
+    #[test]
+    fn run_length_encoded_scanline_matches_flat_one() {
+        let mut data = vec![2, 2, 0, 8];
+        // Per channel, a run of three, a literal pixel and a run of four.
+        for ((first, literal), last) in PIXELS[0].iter().zip(PIXELS[3]).zip(PIXELS[4]) {
+            data.extend([128 + 3, *first, 1, literal, 128 + 4, last]);
+        }
+
+        assert_eq!(read_scanline(&data).unwrap(), PIXELS);
+    }
+
+    #[test]
+    fn run_past_the_scanline_end_is_rejected() {
+        let data = [2, 2, 0, 8, 128 + 9, 0];
+        assert_eq!(read_scanline(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,13 +1,5 @@
-pub const IMAGE_WIDTH: usize = 1024;
-pub const IMAGE_HEIGHT: usize = 1024;
-
-pub const BOUNCES: usize = 6;
-
-#[cfg(debug_assertions)]
-pub const SAMPLES: usize = 32;
-
-#[cfg(not(debug_assertions))]
-pub const SAMPLES: usize = 4096;
+mod settings;
+pub use settings::{RenderSettings, CameraSelection, Panorama, EnvironmentSource, LensSettings, SkySettings};
 
 mod render;
 pub use render::render_scene;
@@ -21,6 +13,8 @@ mod primitive;
 
 mod triangle;
 
+mod microfacet;
+
 mod material;
 use material::Material;
 
@@ -30,5 +24,17 @@ use sampler::{Texture, Sampler};
 mod bvh;
 use bvh::Bvh;
 
+mod light;
+use light::Lights;
+
+mod light_bvh;
+
+mod animation;
+
 mod camera;
 use camera::Camera;
+
+mod environment;
+use environment::Environment;
+
+mod sky;
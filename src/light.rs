@@ -0,0 +1,254 @@
+use crate::{
+    primitive::{Aabb, Color, Ray},
+    triangle::{Triangle, HitRecord},
+    light_bvh::{LightBvh, LightBounds, LightRef},
+    Material,
+    Scene
+};
+
+use std::f32::consts::PI;
+use glam::Vec3A;
+use fastrand::f32;
+
+// Offset of shadow rays to avoid self-intersection at both ends.
+const SHADOW_EPSILON: f32 = 1e-4;
+
+// glTF lights are photometric, while emission is radiometric like Blender's emission strength.
+const LUMENS_PER_WATT: f32 = 683.0;
+
+#[derive(Debug)]
+pub struct Lights {
+    bvh: LightBvh,
+    triangle_count: usize,
+    punctual: Vec<PunctualLight>
+}
+
+// Infinitesimally small light from KHR_lights_punctual.
+#[derive(Debug)]
+pub struct PunctualLight {
+    pub kind: PunctualKind,
+    // Color times intensity, in candela for point and spot lights and lux for directional lights.
+    pub intensity: Color,
+    pub position: Vec3A,
+    // Direction the light points to.
+    pub direction: Vec3A,
+    // Distance at which the light falls off to zero.
+    pub range: Option<f32>
+}
+
+#[derive(Debug)]
+pub enum PunctualKind {
+    Point,
+    Spot { cos_inner: f32, cos_outer: f32 },
+    Directional
+}
+
+#[derive(Debug)]
+pub struct LightSample {
+    pub direction: Vec3A,
+    pub distance: f32,
+    // Radiance arriving from the light, or irradiance for delta lights.
+    pub radiance: Color,
+    // Solid angle density, or the selection probability for delta lights.
+    pub pdf: f32,
+    // Delta lights can only be reached by light sampling.
+    pub delta: bool
+}
+
+impl Lights {
+    pub fn new(triangles: &[Triangle], materials: &[Material], punctual: Vec<PunctualLight>) -> Self {
+        let emissive_triangles: Vec<(LightRef, LightBounds)> = triangles
+            .iter()
+            .enumerate()
+            .filter(|(_, tri)| tri.material_index
+                .is_some_and(|i| matches!(materials[i], Material::Emissive(_))))
+            .map(|(i, tri)| (LightRef::Triangle(i), triangle_bounds(tri, materials)))
+            .collect();
+
+        let triangle_count = emissive_triangles.len();
+
+        // Directional lights reach every point equally and are sampled separately.
+        let punctual_lights = punctual
+            .iter()
+            .enumerate()
+            .filter_map(|(i, light)| light.bounds().map(|bounds| (LightRef::Punctual(i), bounds)));
+
+        Self {
+            bvh: LightBvh::new(emissive_triangles.into_iter().chain(punctual_lights).collect()),
+            triangle_count,
+            punctual
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.triangle_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triangle_count == 0
+    }
+
+    pub fn punctual_len(&self) -> usize {
+        self.punctual.len()
+    }
+
+    // One sample per directional light, as they cannot be told apart by position.
+    pub fn directional_samples(&self, point: Vec3A) -> impl Iterator<Item = LightSample> + '_ {
+        self.punctual
+            .iter()
+            .filter(|light| matches!(light.kind, PunctualKind::Directional))
+            .filter_map(move |light| light.sample(point))
+    }
+
+    // Light picked by its estimated contribution at the point, with a uniform point on triangles posed at the time.
+    pub fn sample(&self, point: Vec3A, normal: Vec3A, time: f32, scene: &Scene) -> Option<LightSample> {
+        let (light, pmf) = self.bvh.sample(point, normal)?;
+
+        let mut light_sample = match light {
+            LightRef::Triangle(i) => sample_triangle(&scene.bvh.triangle_at(i, time), point, scene)?,
+            LightRef::Punctual(i) => self.punctual[i].sample(point)?
+        };
+
+        light_sample.pdf *= pmf;
+        Some(light_sample)
+    }
+
+    // Solid angle density with which `sample` would have chosen the hit point on an emissive triangle.
+    pub fn pdf(&self, origin: Vec3A, normal: Vec3A, hit_record: &HitRecord, scene: &Scene) -> f32 {
+        let pmf = self.bvh.pmf(origin, normal, LightRef::Triangle(hit_record.triangle_index));
+        if pmf <= 0.0 {
+            return 0.0;
+        }
+
+        let triangle = scene.bvh.triangle_at(hit_record.triangle_index, hit_record.time);
+
+        let to_light = hit_record.point - origin;
+        let distance_squared = to_light.length_squared();
+        let direction = to_light / distance_squared.sqrt();
+
+        let cos_light = triangle.geometric_normal().dot(direction).abs();
+        if cos_light <= 0.0 {
+            return 0.0;
+        }
+
+        pmf * distance_squared / (cos_light * triangle.area())
+    }
+}
+
+// Uniformly distributed point on an emissive triangle, with the density of choosing it once the triangle is picked.
+fn sample_triangle(triangle: &Triangle, point: Vec3A, scene: &Scene) -> Option<LightSample> {
+    let (light_point, uv) = triangle.sample_point(f32(), f32());
+    let to_light = light_point - point;
+    let distance_squared = to_light.length_squared();
+    let distance = distance_squared.sqrt();
+    let direction = to_light / distance;
+
+    let cos_light = triangle.geometric_normal().dot(direction).abs();
+    if cos_light <= 0.0 {
+        return None;
+    }
+
+    let Some(Material::Emissive(emissive)) = triangle.material_index.map(|i| &scene.materials[i]) else {
+        return None;
+    };
+
+    Some(LightSample {
+        direction,
+        distance,
+        radiance: emissive.radiance(uv, scene),
+        pdf: distance_squared / (cos_light * triangle.area()),
+        delta: false
+    })
+}
+
+// Emitting from both sides into the hemisphere around the normal, or any direction while moving.
+fn triangle_bounds(triangle: &Triangle, materials: &[Material]) -> LightBounds {
+    LightBounds {
+        aabb: triangle.bounds,
+        power: 2.0 * PI * triangle.area() * emitted_power(triangle, materials),
+        axis: triangle.geometric_normal(),
+        cos_theta_o: if triangle.motion.is_some() { -1.0 } else { 1.0 },
+        cos_theta_e: 0.0,
+        two_sided: true
+    }
+}
+
+impl PunctualLight {
+    // Spot lights fall off from the inner cone to the outer one, point lights emit everywhere.
+    fn bounds(&self) -> Option<LightBounds> {
+        let (axis, cos_theta_o, cos_theta_e) = match self.kind {
+            PunctualKind::Point => (Vec3A::Z, -1.0, 0.0),
+            PunctualKind::Spot { cos_inner, cos_outer } => {
+                let falloff = cos_outer.acos() - cos_inner.acos();
+                (self.direction, cos_inner, falloff.max(0.0).cos())
+            },
+            PunctualKind::Directional => return None
+        };
+
+        Some(LightBounds {
+            aabb: Aabb::new(self.position, self.position),
+            power: 4.0 * PI * self.intensity.luminance() / LUMENS_PER_WATT,
+            axis,
+            cos_theta_o,
+            cos_theta_e,
+            two_sided: false
+        })
+    }
+
+    fn sample(&self, point: Vec3A) -> Option<LightSample> {
+        let intensity = self.intensity / LUMENS_PER_WATT;
+
+        if let PunctualKind::Directional = self.kind {
+            return Some(LightSample {
+                direction: -self.direction,
+                distance: f32::INFINITY,
+                radiance: intensity,
+                pdf: 1.0,
+                delta: true
+            });
+        }
+
+        let to_light = self.position - point;
+        let distance_squared = to_light.length_squared();
+        let distance = distance_squared.sqrt();
+        let direction = to_light / distance;
+
+        let mut attenuation = distance_squared.recip();
+
+        // Smooth window recommended by the glTF specification.
+        if let Some(range) = self.range {
+            attenuation *= (1.0 - (distance / range).powi(4)).clamp(0.0, 1.0).powi(2);
+        }
+
+        if let PunctualKind::Spot { cos_inner, cos_outer } = self.kind {
+            let cos_angle = self.direction.dot(-direction);
+            let falloff = ((cos_angle - cos_outer) / (cos_inner - cos_outer).max(1e-3)).clamp(0.0, 1.0);
+            attenuation *= falloff * falloff;
+        }
+
+        (attenuation > 0.0).then(|| LightSample {
+            direction,
+            distance,
+            radiance: intensity * attenuation,
+            pdf: 1.0,
+            delta: true
+        })
+    }
+}
+
+fn emitted_power(triangle: &Triangle, materials: &[Material]) -> f32 {
+    match triangle.material_index.map(|i| &materials[i]) {
+        Some(Material::Emissive(emissive)) => emissive.power(),
+        _ => 0.0
+    }
+}
+
+impl LightSample {
+    pub fn shadow_ray(&self, origin: Vec3A, time: f32) -> Ray {
+        Ray::new(origin + self.direction * SHADOW_EPSILON, self.direction).with_time(time)
+    }
+
+    pub fn is_visible(&self, origin: Vec3A, time: f32, scene: &Scene) -> bool {
+        !scene.occluded(&self.shadow_ray(origin, time), self.distance - 2.0 * SHADOW_EPSILON)
+    }
+}
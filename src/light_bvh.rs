@@ -0,0 +1,298 @@
+// Light hierarchy that picks lights by their estimated contribution at a shading point, as in pbrt-v4.
+
+use crate::primitive::Aabb;
+
+use std::{collections::HashMap, f32::consts::PI};
+use glam::{Quat, Vec3A};
+use fastrand::f32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LightRef {
+    // Index into the BVH triangles.
+    Triangle(usize),
+    // Index into the punctual lights.
+    Punctual(usize)
+}
+
+// Spatial and directional extent of the emission of one or more lights.
+#[derive(Debug, Clone, Copy)]
+pub struct LightBounds {
+    pub aabb: Aabb,
+    pub power: f32,
+    // Cone of normals around `axis`, widened by the emission spread around each normal.
+    pub axis: Vec3A,
+    pub cos_theta_o: f32,
+    pub cos_theta_e: f32,
+    pub two_sided: bool
+}
+
+#[derive(Debug)]
+pub struct LightBvh {
+    nodes: Vec<LightNode>,
+    // Path from the root to each light, one bit per level set when the second child is taken.
+    trails: HashMap<LightRef, u64>
+}
+
+#[derive(Debug)]
+struct LightNode {
+    bounds: LightBounds,
+    content: NodeContent
+}
+
+#[derive(Debug)]
+enum NodeContent {
+    Leaf(LightRef),
+    // The first child directly follows its parent.
+    Interior { second_child: usize }
+}
+
+impl LightBvh {
+    pub fn new(mut lights: Vec<(LightRef, LightBounds)>) -> Self {
+        lights.retain(|(_, bounds)| bounds.power > 0.0);
+
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(lights.len() * 2),
+            trails: HashMap::with_capacity(lights.len())
+        };
+
+        if !lights.is_empty() {
+            bvh.build(&mut lights, 0, 0);
+        }
+
+        bvh
+    }
+
+    // Light chosen proportional to the importance of the nodes along the way, and the probability of choosing it.
+    pub fn sample(&self, point: Vec3A, normal: Vec3A) -> Option<(LightRef, f32)> {
+        let mut index = 0;
+        let mut pmf = 1.0;
+
+        loop {
+            match self.nodes.get(index)?.content {
+                NodeContent::Leaf(light) => return Some((light, pmf)),
+                NodeContent::Interior { second_child } => {
+                    let first = self.nodes[index + 1].bounds.importance(point, normal);
+                    let second = self.nodes[second_child].bounds.importance(point, normal);
+                    if first + second <= 0.0 {
+                        return None;
+                    }
+
+                    let p_first = first / (first + second);
+                    if f32() < p_first {
+                        index += 1;
+                        pmf *= p_first;
+                    } else {
+                        index = second_child;
+                        pmf *= 1.0 - p_first;
+                    }
+                }
+            }
+        }
+    }
+
+    // Probability with which `sample` chooses the light.
+    pub fn pmf(&self, point: Vec3A, normal: Vec3A, light: LightRef) -> f32 {
+        let Some(&trail) = self.trails.get(&light) else {
+            return 0.0;
+        };
+
+        let mut index = 0;
+        let mut pmf = 1.0;
+        let mut depth = 0;
+
+        while let NodeContent::Interior { second_child } = self.nodes[index].content {
+            let first = self.nodes[index + 1].bounds.importance(point, normal);
+            let second = self.nodes[second_child].bounds.importance(point, normal);
+            if first + second <= 0.0 {
+                return 0.0;
+            }
+
+            if (trail >> depth) & 1 == 0 {
+                index += 1;
+                pmf *= first / (first + second);
+            } else {
+                index = second_child;
+                pmf *= second / (first + second);
+            }
+
+            depth += 1;
+        }
+
+        pmf
+    }
+
+    // Splits at the median along the longest axis of the light centers.
+    fn build(&mut self, lights: &mut [(LightRef, LightBounds)], trail: u64, depth: u32) -> LightBounds {
+        if let [(light, bounds)] = lights {
+            self.trails.insert(*light, trail);
+            self.nodes.push(LightNode { bounds: *bounds, content: NodeContent::Leaf(*light) });
+            return *bounds;
+        }
+
+        let mut centers = Aabb::MAX;
+        for (_, bounds) in lights.iter() {
+            centers.grow(bounds.aabb.center());
+        }
+
+        let extent = centers.maximum - centers.minimum;
+        let axis = if extent.x > extent.y.max(extent.z) { 0 } else if extent.y > extent.z { 1 } else { 2 };
+
+        let middle = lights.len() / 2;
+        lights.select_nth_unstable_by(middle, |(_, a), (_, b)| a.aabb.center()[axis].total_cmp(&b.aabb.center()[axis]));
+
+        let index = self.nodes.len();
+        self.nodes.push(LightNode { bounds: lights[0].1, content: NodeContent::Interior { second_child: 0 } });
+
+        let (first, second) = lights.split_at_mut(middle);
+        let first_bounds = self.build(first, trail, depth + 1);
+        let second_child = self.nodes.len();
+        let second_bounds = self.build(second, trail | 1 << depth, depth + 1);
+
+        let bounds = first_bounds.union(&second_bounds);
+        self.nodes[index] = LightNode { bounds, content: NodeContent::Interior { second_child } };
+        bounds
+    }
+}
+
+impl LightBounds {
+    fn union(&self, other: &Self) -> Self {
+        let (axis, cos_theta_o) = cone_union(self.axis, self.cos_theta_o, other.axis, other.cos_theta_o);
+
+        Self {
+            aabb: self.aabb.union(other.aabb),
+            power: self.power + other.power,
+            axis,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided
+        }
+    }
+
+    // Conservative estimate of the contribution of the bounded lights at a point with the given normal.
+    fn importance(&self, point: Vec3A, normal: Vec3A) -> f32 {
+        let center = self.aabb.center();
+        let diagonal = self.aabb.maximum - self.aabb.minimum;
+        let distance_squared = point.distance_squared(center).max(diagonal.length() * 0.5);
+
+        let to_point = (point - center).normalize_or_zero();
+        let mut cos_theta_w = self.axis.dot(to_point);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = sin_from_cos(cos_theta_w);
+
+        // Directions from the point towards the bounds lie within a cone of this angle.
+        let radius_squared = diagonal.length_squared() * 0.25;
+        let cos_theta_b = match point.distance_squared(center) < radius_squared {
+            true => -1.0,
+            false => (1.0 - radius_squared / point.distance_squared(center)).max(0.0).sqrt()
+        };
+        let sin_theta_b = sin_from_cos(cos_theta_b);
+
+        // Smallest angle between the emission cone and the direction to the point.
+        let sin_theta_o = sin_from_cos(self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.power * cos_theta / distance_squared;
+
+        // The receiver may reflect or transmit, so both sides of it count.
+        let cos_theta_i = normal.dot(to_point).abs();
+        importance *= cos_sub_clamped(sin_from_cos(cos_theta_i), cos_theta_i, sin_theta_b, cos_theta_b);
+
+        importance.max(0.0)
+    }
+}
+
+// Smallest cone containing both cones, as an axis and the cosine of its half angle.
+fn cone_union(a: Vec3A, cos_a: f32, b: Vec3A, cos_b: f32) -> (Vec3A, f32) {
+    let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+    let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+    let theta_d = a.angle_between(b);
+
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (a, cos_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (b, cos_b);
+    }
+
+    let theta_o = (theta_a + theta_d + theta_b) * 0.5;
+    let rotation_axis = a.cross(b);
+    if theta_o >= PI || rotation_axis.length_squared() == 0.0 {
+        return (a, -1.0);
+    }
+
+    let axis = Quat::from_axis_angle(rotation_axis.normalize().into(), theta_o - theta_a) * a;
+    (axis, theta_o.cos())
+}
+
+fn sin_from_cos(cos: f32) -> f32 {
+    (1.0 - cos * cos).max(0.0).sqrt()
+}
+
+// Cosine of the difference of two angles, or 1 if the first is smaller.
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b { 1.0 } else { cos_a * cos_b + sin_a * sin_b }
+}
+
+// Sine of the difference of two angles, or 0 if the first is smaller.
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b { 0.0 } else { sin_a * cos_b - cos_a * sin_b }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn light(index: usize, position: Vec3A, power: f32) -> (LightRef, LightBounds) {
+        (LightRef::Triangle(index), LightBounds {
+            aabb: Aabb::new(position - 0.1, position + 0.1),
+            power,
+            axis: Vec3A::NEG_Y,
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: false
+        })
+    }
+
+    #[test]
+    fn sampling_frequency_matches_pmf() {
+        let lights: Vec<_> = [(-2.0, 1.0), (0.0, 4.0), (1.0, 0.5), (3.0, 2.0), (5.0, 1.0)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(x, power))| light(i, Vec3A::new(x, 2.0, 0.0), power))
+            .collect();
+        let bvh = LightBvh::new(lights.clone());
+        let (point, normal) = (Vec3A::ZERO, Vec3A::Y);
+
+        fastrand::seed(7);
+        const SAMPLES: usize = 200_000;
+        let mut counts = HashMap::new();
+        for _ in 0..SAMPLES {
+            let (light, pmf) = bvh.sample(point, normal).unwrap();
+            assert!((pmf - bvh.pmf(point, normal, light)).abs() < 1e-6);
+            *counts.entry(light).or_insert(0) += 1;
+        }
+
+        let total: f32 = lights.iter().map(|&(light, _)| bvh.pmf(point, normal, light)).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+
+        for (light, _) in lights {
+            let frequency = counts.get(&light).copied().unwrap_or(0) as f32 / SAMPLES as f32;
+            assert!((frequency - bvh.pmf(point, normal, light)).abs() < 0.01, "{:?}", light);
+        }
+    }
+
+    #[test]
+    fn lights_without_power_are_never_chosen() {
+        let bvh = LightBvh::new(vec![light(0, Vec3A::Y, 0.0), light(1, Vec3A::Y, 1.0)]);
+
+        assert_eq!(bvh.pmf(Vec3A::ZERO, Vec3A::Y, LightRef::Triangle(0)), 0.0);
+        assert_eq!(bvh.sample(Vec3A::ZERO, Vec3A::Y), Some((LightRef::Triangle(1), 1.0)));
+    }
+}
@@ -1,47 +1,159 @@
+mod cli;
+use cli::{Args, USAGE};
+
 use std::{
     time::Instant,
     fs::read_dir,
-    io::stdin,
-    path::PathBuf
+    path::{Path, PathBuf},
+    process::exit
 };
 
 use raytracer::{
-    BOUNCES, IMAGE_HEIGHT, IMAGE_WIDTH, SAMPLES,
     Scene,
+    RenderSettings,
+    CameraSelection,
     render_scene,
+    scene::list_cameras,
     util::{save_png, Statistics}
 };
 
 static SCENES_PATH: &str = "scenes/";
+static OUT_PATH: &str = "out/";
 
 fn main() {
+    let args = Args::parse(std::env::args().skip(1)).unwrap_or_else(|e| fail(&e));
+
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    if args.list {
+        list(args.scene.as_deref());
+        return;
+    }
+
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Cannot configure thread pool");
+    }
+
+    let mut settings = args.settings;
+    let scene_path = args.scene.unwrap_or_else(default_scene_path);
+
+    // Checked before the scene is built, which can take a while.
+    let camera_names = list_cameras(&scene_path).unwrap_or_else(|e| fail(&e));
+    if let Err(e) = settings.camera.indices(&camera_names) {
+        fail_camera(&e, &camera_names);
+    }
+
     let mut statistics = Statistics::new();
-    statistics.add_str("Resolution", &format!("{}x{}", IMAGE_WIDTH, IMAGE_HEIGHT));
-    statistics.add("Samples", &SAMPLES);
-    statistics.add("Bounces", &BOUNCES);
+    statistics.add_str("Resolution", &format!("{}x{}", settings.width, settings.height));
+    statistics.add("Samples", &settings.samples);
+    statistics.add("Bounces", &settings.bounces);
 
-    let scene_path = pick_scene_path();
     let mut now = Instant::now();
 
-    let scene = Scene::import(&scene_path);
+    let mut scene = Scene::import(&scene_path, &settings).unwrap_or_else(|e| fail(&e));
     let bvh_elapsed = now.elapsed();
     statistics.add("Triangles", &scene.bvh.triangles.len());
     statistics.add("BVH nodes", &scene.bvh.nodes_used);
+    statistics.add("Emissive triangles", &scene.lights.len());
+    statistics.add("Punctual lights", &scene.lights.punctual_len());
     statistics.add_str("Scene construction time", &format!("{:.2?}", bvh_elapsed));
 
+    let out_path = args.output.unwrap_or_else(|| Path::new(OUT_PATH).join(format!("{}.png", scene.name)));
+
+    // Frames are counted from 1 at time 0, as Blender exports them.
+    let frames: Vec<usize> = match (args.animation, args.frames) {
+        (false, _) => Vec::new(),
+        (true, Some(frames)) => frames.collect(),
+        (true, None) => (1..=(scene.animation_duration() * args.fps).round() as usize + 1).collect()
+    };
+
     now = Instant::now();
-    let pixels = render_scene(&scene);
-    let render_elapsed = now.elapsed();
+    let mut camera_count = 0;
+    match args.animation {
+        false => camera_count = render_cameras(&scene, &settings, &out_path, None),
+        true => for &frame in &frames {
+            let time = (frame - 1) as f32 / args.fps;
+            if time != settings.time {
+                settings.time = time;
+                scene.pose(&settings);
+            }
 
-    save_png(&scene.name, IMAGE_WIDTH, IMAGE_HEIGHT, pixels);
+            camera_count = render_cameras(&scene, &settings, &out_path, Some(frame));
+        }
+    }
+    let render_elapsed = now.elapsed();
 
+    statistics.add("Cameras", &camera_count);
+    if args.animation {
+        statistics.add("Frames", &frames.len());
+    }
     statistics.add_str("Render time", &format!("{:.2?}", render_elapsed));
     statistics.add_str("Total time", &format!("{:.2?}", bvh_elapsed + render_elapsed));
     statistics.print();
 }
 
-fn pick_scene_path() -> PathBuf {
-    let paths = read_dir(SCENES_PATH).expect("No scenes found");
+fn list(scene_path: Option<&Path>) {
+    match scene_path {
+        Some(path) => {
+            let cameras = list_cameras(path).unwrap_or_else(|e| fail(&e));
+            print!("{}", camera_list(&cameras));
+        },
+        None => {
+            for path in scene_paths().unwrap_or_else(|e| fail(&e)) {
+                println!("{}", path.display());
+            }
+        }
+    }
+}
+
+// Renders the selected cameras, suffixing the images with the camera and frame, and returns how many were rendered.
+fn render_cameras(scene: &Scene, settings: &RenderSettings, out_path: &Path, frame: Option<usize>) -> usize {
+    let cameras = scene.selected_cameras(&settings.camera).expect("Camera selection is checked before import");
+
+    for camera in &cameras {
+        let pixels = render_scene(scene, camera, settings);
+
+        let mut path = out_path.to_path_buf();
+        if let CameraSelection::All = settings.camera {
+            let name: String = camera.name
+                .chars()
+                .map(|c| if c.is_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+                .collect();
+            path = suffixed_path(&path, &name);
+        }
+        if let Some(frame) = frame {
+            path = suffixed_path(&path, &format!("{:04}", frame));
+        }
+
+        save_png(&path, settings.width, settings.height, pixels);
+    }
+
+    cameras.len()
+}
+
+// Output path with the suffix appended to the file name, e.g. out/scene_Camera.png.
+fn suffixed_path(out_path: &Path, suffix: &str) -> PathBuf {
+    let stem = out_path.file_stem().map_or("image".into(), |s| s.to_string_lossy());
+    out_path.with_file_name(format!("{}_{}.png", stem, suffix))
+}
+
+fn default_scene_path() -> PathBuf {
+    match scene_paths().unwrap_or_else(|e| fail(&e)).as_slice() {
+        [path] => path.clone(),
+        [] => fail("No scenes found."),
+        _ => fail("Multiple scenes found, pass the scene path as an argument.")
+    }
+}
+
+fn scene_paths() -> Result<Vec<PathBuf>, String> {
+    let paths = read_dir(SCENES_PATH)
+        .map_err(|e| format!("No scenes directory '{}' to find scenes in: {}", SCENES_PATH, e))?;
     let mut scene_paths: Vec<PathBuf> = paths
         .filter_map(|res| res.ok().map(|entry| entry.path()))
         .filter(|path| path
@@ -49,24 +161,28 @@ fn pick_scene_path() -> PathBuf {
             .is_some_and(|ext| ext == "glb"))
         .collect();
     scene_paths.sort_unstable();
+    Ok(scene_paths)
+}
 
-    if scene_paths.is_empty() {
-        panic!("No scenes found.");
-    }
-
-    if scene_paths.len() == 1 {
-        return scene_paths[0].clone();
-    }
+// Numbered camera names, one per line, as listed by --list.
+fn camera_list(names: &[String]) -> String {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| format!("{}: {}\n", i, name))
+        .collect()
+}
 
-    println!("Pick a scene:");
-    for (i, path) in scene_paths.iter().enumerate() {
-        println!("{}: {}", i + 1, path.file_stem().unwrap().to_str().unwrap());
+// Reports a camera selection the scene cannot satisfy, with the cameras it has instead of the usage.
+fn fail_camera(message: &str, names: &[String]) -> ! {
+    match names.is_empty() {
+        true => eprintln!("{}", message),
+        false => eprint!("{}\n\nCameras:\n{}", message, camera_list(names))
     }
+    exit(2);
+}
 
-    let mut input = String::new();
-    let _ = stdin().read_line(&mut input);
-    let i = input.trim().parse::<usize>().expect("Cannot parse input");
-    println!("");
-
-    scene_paths.get(i - 1).expect("Scene number out of range").clone()
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(2);
 }
@@ -1,20 +1,88 @@
 use crate::{
-    primitive::{Color, Ray},
+    primitive::{Color, Frame, Ray},
     util::random_unit_vector,
     triangle::HitRecord,
+    sampler::NormalMap,
+    microfacet,
     Sampler,
     Scene
 };
 
+use glam::{Vec2, Vec3A};
 use fastrand::f32;
+use std::f32::consts::FRAC_1_PI;
 
 pub trait Scatterable {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> (Option<Ray>, Color);
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> Option<ScatterRecord>;
+
+    // BSDF times the cosine term for light arriving from the given direction.
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> Color;
+
+    // Solid angle density with which `scatter` samples the given direction.
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> f32;
+
+    fn emitted(&self, _hit_record: &HitRecord, _scene: &Scene) -> Color {
+        Color::BLACK
+    }
+}
+
+#[derive(Debug)]
+pub struct ScatterRecord {
+    pub ray: Ray,
+    // BSDF times the cosine term divided by the pdf.
+    pub attenuation: Color,
+    pub pdf: f32,
+    // Perfectly specular directions cannot be hit by light sampling.
+    pub specular: bool
+}
+
+impl ScatterRecord {
+    fn new(hit_record: &HitRecord, direction: Vec3A, attenuation: Color, pdf: f32) -> Self {
+        Self {
+            ray: Ray::new(hit_record.point + direction * 1e-5, direction).with_time(hit_record.time),
+            attenuation,
+            pdf,
+            specular: false
+        }
+    }
+
+    fn specular(hit_record: &HitRecord, direction: Vec3A, attenuation: Color) -> Self {
+        Self {
+            specular: true,
+            ..Self::new(hit_record, direction, attenuation, 0.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AlphaMode {
+    // Surfaces with alpha below the cutoff are fully transparent.
+    Mask(f32),
+    // Alpha is the probability of a ray hitting the surface.
+    Blend
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Alpha {
+    pub mode: AlphaMode,
+    pub factor: f32,
+    pub sampler: Sampler
+}
+
+impl Alpha {
+    pub fn is_opaque(&self, uv: Option<Vec2>, scene: &Scene) -> bool {
+        let alpha = self.factor * self.sampler.sample_alpha(uv, scene);
+        match self.mode {
+            AlphaMode::Mask(cutoff) => alpha >= cutoff,
+            AlphaMode::Blend => f32() < alpha
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Material {
     Diffuse(Diffuse),
+    Pbr(Pbr),
     Metal(Metal),
     Glass(Glass),
     Emissive(Emissive),
@@ -25,60 +93,352 @@ impl Material {
         use Material::*;
         match self {
             Diffuse(diffuse) => diffuse.color_sampler.texture_index(),
+            Pbr(pbr) => pbr.color_sampler.texture_index(),
             Metal(metal) => metal.color_sampler.texture_index(),
-            Glass(glass) => glass.color_sampler.texture_index(),
+            Glass(glass) => glass.surface.color_sampler.texture_index(),
+            Emissive(emissive) => emissive.emission_sampler.texture_index()
+        }
+    }
+
+    pub fn normal_map(&self) -> Option<&NormalMap> {
+        use Material::*;
+        match self {
+            Diffuse(diffuse) => diffuse.normal_map.as_ref(),
+            Pbr(pbr) => pbr.normal_map.as_ref(),
+            Metal(metal) => metal.normal_map.as_ref(),
+            Glass(glass) => glass.surface.normal_map.as_ref(),
             _ => None
         }
     }
+
+    pub fn alpha(&self) -> Option<&Alpha> {
+        use Material::*;
+        match self {
+            Diffuse(diffuse) => diffuse.alpha.as_ref(),
+            Pbr(pbr) => pbr.alpha.as_ref(),
+            Metal(metal) => metal.alpha.as_ref(),
+            Glass(glass) => glass.surface.alpha.as_ref(),
+            Emissive(emissive) => emissive.alpha.as_ref()
+        }
+    }
+
+    // Whether part of the scattering can be reached by light sampling, regardless of the lobe `scatter` picks.
+    pub fn has_non_delta_lobe(&self, hit_record: &HitRecord, scene: &Scene) -> bool {
+        match self {
+            Material::Diffuse(_) | Material::Pbr(_) => true,
+            Material::Metal(metal) => metal.roughness_factor > 0.0,
+            Material::Glass(glass) => glass.transmission(hit_record, scene) < 1.0,
+            Material::Emissive(_) => false
+        }
+    }
+
+    // Absorption along a ray segment of the given length travelled inside the material.
+    pub fn transmittance(&self, distance: f32) -> Color {
+        match self {
+            Material::Glass(glass) => glass.transmittance(distance),
+            _ => Color::WHITE
+        }
+    }
 }
 
 impl Scatterable for Material {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> (Option<Ray>, Color) {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> Option<ScatterRecord> {
         use Material::*;
         match self {
             Diffuse(diffuse) => diffuse.scatter(ray, hit_record, scene),
+            Pbr(pbr) => pbr.scatter(ray, hit_record, scene),
             Metal(metal) => metal.scatter(ray, hit_record, scene),
             Glass(glass) => glass.scatter(ray, hit_record, scene),
             Emissive(emissive) => emissive.scatter(ray, hit_record, scene),
         }
     }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> Color {
+        use Material::*;
+        match self {
+            Diffuse(diffuse) => diffuse.eval(ray, hit_record, direction, scene),
+            Pbr(pbr) => pbr.eval(ray, hit_record, direction, scene),
+            Metal(metal) => metal.eval(ray, hit_record, direction, scene),
+            Glass(glass) => glass.eval(ray, hit_record, direction, scene),
+            Emissive(emissive) => emissive.eval(ray, hit_record, direction, scene),
+        }
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> f32 {
+        use Material::*;
+        match self {
+            Diffuse(diffuse) => diffuse.pdf(ray, hit_record, direction, scene),
+            Pbr(pbr) => pbr.pdf(ray, hit_record, direction, scene),
+            Metal(metal) => metal.pdf(ray, hit_record, direction, scene),
+            Glass(glass) => glass.pdf(ray, hit_record, direction, scene),
+            Emissive(emissive) => emissive.pdf(ray, hit_record, direction, scene),
+        }
+    }
+
+    fn emitted(&self, hit_record: &HitRecord, scene: &Scene) -> Color {
+        use Material::*;
+        match self {
+            Emissive(emissive) => emissive.emitted(hit_record, scene),
+            _ => Color::BLACK
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Diffuse {
-    pub color_sampler: Sampler
+    pub color_sampler: Sampler,
+    pub normal_map: Option<NormalMap>,
+    pub alpha: Option<Alpha>
 }
 
 impl Scatterable for Diffuse {
-    fn scatter(&self, _ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> (Option<Ray>, Color) {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> Option<ScatterRecord> {
         let ray_direction = (hit_record.normal + random_unit_vector()).normalize();
-        let color = self.color_sampler.sample(hit_record.uv, scene);
-        (Some(Ray::new(hit_record.point + ray_direction * 1e-5, ray_direction)), color)
+        let color = self.color_sampler.sample_at(hit_record, scene);
+        let pdf = self.pdf(ray, hit_record, ray_direction, scene);
+        Some(ScatterRecord::new(hit_record, ray_direction, color, pdf))
+    }
+
+    fn eval(&self, _ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> Color {
+        let cos_theta = hit_record.normal.dot(direction);
+        if cos_theta <= 0.0 {
+            return Color::BLACK;
+        }
+
+        self.color_sampler.sample_at(hit_record, scene) * (cos_theta * FRAC_1_PI)
+    }
+
+    fn pdf(&self, _ray: &Ray, hit_record: &HitRecord, direction: Vec3A, _scene: &Scene) -> f32 {
+        hit_record.normal.dot(direction).max(0.0) * FRAC_1_PI
+    }
+}
+
+// glTF metallic-roughness material, blending a GGX-coated Lambertian base with a GGX conductor.
+#[derive(Debug)]
+pub struct Pbr {
+    pub color_sampler: Sampler,
+    // Roughness is read from the green channel and metallic from the blue channel.
+    pub metallic_roughness_sampler: Sampler,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    // Determines the reflectance of the dielectric specular layer.
+    pub ior: f32,
+    pub normal_map: Option<NormalMap>,
+    pub alpha: Option<Alpha>
+}
+
+struct PbrParameters {
+    base_color: Color,
+    metallic: f32,
+    alpha: f32
+}
+
+impl Pbr {
+    fn parameters(&self, hit_record: &HitRecord, scene: &Scene) -> PbrParameters {
+        let metallic_roughness = self.metallic_roughness_sampler.sample_at(hit_record, scene);
+
+        PbrParameters {
+            base_color: self.color_sampler.sample_at(hit_record, scene),
+            metallic: self.metallic_factor * metallic_roughness.b,
+            alpha: microfacet::alpha_from_roughness(self.roughness_factor * metallic_roughness.g)
+        }
+    }
+
+    fn specular_probability(parameters: &PbrParameters) -> f32 {
+        0.5 + 0.5 * parameters.metallic
+    }
+
+    // Reflectance of the dielectric layer at normal incidence.
+    fn dielectric_f0(&self) -> f32 {
+        ((self.ior - 1.0) / (self.ior + 1.0)).powi(2)
+    }
+
+    fn eval_local(&self, wo: Vec3A, wi: Vec3A, parameters: &PbrParameters) -> Color {
+        let h = (wo + wi).normalize();
+        let v_dot_h = wo.dot(h);
+        let specular = microfacet::specular(wo, wi, h, parameters.alpha);
+
+        let f0 = self.dielectric_f0();
+        let dielectric_fresnel = f0 + (1.0 - f0) * (1.0 - v_dot_h).max(0.0).powi(5);
+        let dielectric = parameters.base_color * FRAC_1_PI * (1.0 - dielectric_fresnel) + specular * dielectric_fresnel;
+        let metal = microfacet::fresnel_schlick(parameters.base_color, v_dot_h) * specular;
+
+        (dielectric * (1.0 - parameters.metallic) + metal * parameters.metallic) * wi.z
+    }
+
+    fn pdf_local(wo: Vec3A, wi: Vec3A, parameters: &PbrParameters) -> f32 {
+        let h = (wo + wi).normalize();
+        let specular_probability = Self::specular_probability(parameters);
+
+        specular_probability * microfacet::reflection_pdf(wo, h, parameters.alpha)
+            + (1.0 - specular_probability) * wi.z * FRAC_1_PI
     }
 }
 
+impl Scatterable for Pbr {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> Option<ScatterRecord> {
+        let frame = Frame::from_normal(hit_record.normal);
+        let wo = frame.to_local(-ray.direction);
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let parameters = self.parameters(hit_record, scene);
+
+        let wi = if f32() < Self::specular_probability(&parameters) {
+            let h = microfacet::sample_visible_normal(wo, parameters.alpha, f32(), f32());
+            microfacet::reflect(wo, h)
+        } else {
+            frame.to_local((hit_record.normal + random_unit_vector()).normalize())
+        };
+
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        let pdf = Self::pdf_local(wo, wi, &parameters);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        let attenuation = self.eval_local(wo, wi, &parameters) / pdf;
+        Some(ScatterRecord::new(hit_record, frame.to_world(wi), attenuation, pdf))
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> Color {
+        let frame = Frame::from_normal(hit_record.normal);
+        let wo = frame.to_local(-ray.direction);
+        let wi = frame.to_local(direction);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Color::BLACK;
+        }
+
+        self.eval_local(wo, wi, &self.parameters(hit_record, scene))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> f32 {
+        let frame = Frame::from_normal(hit_record.normal);
+        let wo = frame.to_local(-ray.direction);
+        let wi = frame.to_local(direction);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+
+        Self::pdf_local(wo, wi, &self.parameters(hit_record, scene))
+    }
+}
+
+// Untextured fully metallic surface like the metal part of `Pbr`, a perfect mirror at zero roughness.
 #[derive(Debug)]
 pub struct Metal {
-    pub color_sampler: Sampler
+    pub color_sampler: Sampler,
+    pub roughness_factor: f32,
+    pub normal_map: Option<NormalMap>,
+    pub alpha: Option<Alpha>
+}
+
+impl Metal {
+    // The base color is the reflectance at normal incidence, rising towards white at grazing angles.
+    fn eval_local(wo: Vec3A, wi: Vec3A, color: Color, alpha: f32) -> Color {
+        let h = (wo + wi).normalize();
+        microfacet::fresnel_schlick(color, wo.dot(h)) * microfacet::specular(wo, wi, h, alpha) * wi.z
+    }
 }
 
 impl Scatterable for Metal {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> (Option<Ray>, Color) {
-        let reflection_dir = ray.direction.reflect(hit_record.normal).normalize();
-        let color = self.color_sampler.sample(hit_record.uv, scene);
-        (Some(Ray::new(hit_record.point + reflection_dir * 1e-5, reflection_dir)), color)
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> Option<ScatterRecord> {
+        let color = self.color_sampler.sample_at(hit_record, scene);
+        let roughness = self.roughness_factor;
+
+        if roughness <= 0.0 {
+            let reflection_dir = ray.direction.reflect(hit_record.normal).normalize();
+            let fresnel = microfacet::fresnel_schlick(color, -ray.direction.dot(hit_record.normal));
+            return Some(ScatterRecord::specular(hit_record, reflection_dir, fresnel));
+        }
+
+        let frame = Frame::from_normal(hit_record.normal);
+        let wo = frame.to_local(-ray.direction);
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let alpha = microfacet::alpha_from_roughness(roughness);
+        let h = microfacet::sample_visible_normal(wo, alpha, f32(), f32());
+        let wi = microfacet::reflect(wo, h);
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        let pdf = microfacet::reflection_pdf(wo, h, alpha);
+        let attenuation = Self::eval_local(wo, wi, color, alpha) / pdf;
+        Some(ScatterRecord::new(hit_record, frame.to_world(wi), attenuation, pdf))
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> Color {
+        let roughness = self.roughness_factor;
+        let frame = Frame::from_normal(hit_record.normal);
+        let wo = frame.to_local(-ray.direction);
+        let wi = frame.to_local(direction);
+        if roughness <= 0.0 || wo.z <= 0.0 || wi.z <= 0.0 {
+            return Color::BLACK;
+        }
+
+        let color = self.color_sampler.sample_at(hit_record, scene);
+        Self::eval_local(wo, wi, color, microfacet::alpha_from_roughness(roughness))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, _scene: &Scene) -> f32 {
+        let roughness = self.roughness_factor;
+        let frame = Frame::from_normal(hit_record.normal);
+        let wo = frame.to_local(-ray.direction);
+        let wi = frame.to_local(direction);
+        if roughness <= 0.0 || wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+
+        microfacet::reflection_pdf(wo, (wo + wi).normalize(), microfacet::alpha_from_roughness(roughness))
     }
 }
 
+// Dielectric that transmits a fraction of the incoming light and lets the rest interact with the underlying surface.
 #[derive(Debug)]
 pub struct Glass {
-    pub color_sampler: Sampler
+    pub ior: f32,
+    // Transmission is read from the red channel, as in KHR_materials_transmission textures.
+    pub transmission_sampler: Sampler,
+    pub transmission_factor: f32,
+    // Color that white light turns into after travelling the attenuation distance inside the volume.
+    pub attenuation_color: Color,
+    pub attenuation_distance: f32,
+    pub surface: Pbr
+}
+
+impl Glass {
+    // Beer-Lambert law.
+    pub fn transmittance(&self, distance: f32) -> Color {
+        if self.attenuation_distance.is_infinite() {
+            return Color::WHITE;
+        }
+
+        self.attenuation_color.powf(distance / self.attenuation_distance)
+    }
+
+    fn transmission(&self, hit_record: &HitRecord, scene: &Scene) -> f32 {
+        self.transmission_factor * self.transmission_sampler.sample_at(hit_record, scene).r
+    }
 }
 
 impl Scatterable for Glass {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> (Option<Ray>, Color) {
-        const GLASS_IOR: f32 = 1.52;
-        let eta = if hit_record.front_face { GLASS_IOR.recip() } else { GLASS_IOR };
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, scene: &Scene) -> Option<ScatterRecord> {
+        let transmission = self.transmission(hit_record, scene);
+
+        if f32() >= transmission {
+            return self.surface
+                .scatter(ray, hit_record, scene)
+                .map(|scatter| ScatterRecord { pdf: scatter.pdf * (1.0 - transmission), ..scatter });
+        }
+
+        let eta = if hit_record.front_face { self.ior.recip() } else { self.ior };
 
         let cos_theta = (-ray.direction).dot(hit_record.normal).min(1.0);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
@@ -90,24 +450,61 @@ impl Scatterable for Glass {
             ray.direction.refract(hit_record.normal, eta)
         };
 
-        let color = self.color_sampler.sample(hit_record.uv, scene);
-        (Some(Ray::new(hit_record.point + direction * 1e-5, direction)), color)
+        // Tinting is left to the absorption inside the volume, so both lobes are white.
+        Some(ScatterRecord::specular(hit_record, direction, Color::WHITE))
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> Color {
+        let transmission = self.transmission(hit_record, scene);
+        self.surface.eval(ray, hit_record, direction, scene) * (1.0 - transmission)
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3A, scene: &Scene) -> f32 {
+        let transmission = self.transmission(hit_record, scene);
+        self.surface.pdf(ray, hit_record, direction, scene) * (1.0 - transmission)
     }
 }
 
 // Schlick's approximation.
 fn reflectance(cosine: f32, ior: f32) -> f32 {
-    let r0 = (1.0 - ior) / (1.0 + ior).powf(2.0);
+    let r0 = ((1.0 - ior) / (1.0 + ior)).powi(2);
     r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
 }
 
+// Unclamped emission: the emissive texture times the emissive factor, scaled by KHR_materials_emissive_strength.
 #[derive(Debug)]
 pub struct Emissive {
-    pub color: Color
+    pub emission_sampler: Sampler,
+    pub factor: Color,
+    pub strength: f32,
+    pub alpha: Option<Alpha>
+}
+
+impl Emissive {
+    pub fn radiance(&self, uv: Option<Vec2>, scene: &Scene) -> Color {
+        self.emission_sampler.sample(uv, scene) * self.factor * self.strength
+    }
+
+    // Upper bound of the emitted radiance, used to weight light selection.
+    pub fn power(&self) -> f32 {
+        self.factor.luminance() * self.strength
+    }
 }
 
 impl Scatterable for Emissive {
-    fn scatter(&self, _ray: &Ray, _hit_record: &HitRecord, _scene: &Scene) -> (Option<Ray>, Color) {
-        (None, self.color)
+    fn scatter(&self, _ray: &Ray, _hit_record: &HitRecord, _scene: &Scene) -> Option<ScatterRecord> {
+        None
+    }
+
+    fn eval(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: Vec3A, _scene: &Scene) -> Color {
+        Color::BLACK
+    }
+
+    fn pdf(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: Vec3A, _scene: &Scene) -> f32 {
+        0.0
+    }
+
+    fn emitted(&self, hit_record: &HitRecord, scene: &Scene) -> Color {
+        self.radiance(hit_record.uv, scene)
     }
 }
@@ -0,0 +1,67 @@
+// GGX (Trowbridge-Reitz) microfacet functions. Directions are in a local shading frame with the normal along +z.
+
+use crate::primitive::Color;
+
+use glam::Vec3A;
+use std::f32::consts::{PI, TAU};
+
+// Keeps the distribution finite for perfectly smooth surfaces.
+const MIN_ALPHA: f32 = 1e-3;
+
+pub fn alpha_from_roughness(roughness: f32) -> f32 {
+    (roughness * roughness).max(MIN_ALPHA)
+}
+
+pub fn distribution(h: Vec3A, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = h.z * h.z * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+// Smith masking term for a single direction.
+pub fn smith_g1(v: Vec3A, alpha: f32) -> f32 {
+    let cos2 = v.z * v.z;
+    let tan2 = (1.0 - cos2) / cos2;
+    2.0 / (1.0 + (1.0 + alpha * alpha * tan2).sqrt())
+}
+
+// Samples a microfacet normal proportional to its visible area from `wo`, "Sampling Visible GGX Normals" (Heitz 2018).
+pub fn sample_visible_normal(wo: Vec3A, alpha: f32, u1: f32, u2: f32) -> Vec3A {
+    let vh = Vec3A::new(alpha * wo.x, alpha * wo.y, wo.z).normalize();
+
+    let len_sq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if len_sq > 0.0 {
+        Vec3A::new(-vh.y, vh.x, 0.0) / len_sq.sqrt()
+    } else {
+        Vec3A::X
+    };
+    let t2 = vh.cross(t1);
+
+    let r = u1.sqrt();
+    let phi = TAU * u2;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+    Vec3A::new(alpha * nh.x, alpha * nh.y, nh.z.max(0.0)).normalize()
+}
+
+// Density of a direction reflected about a normal sampled by `sample_visible_normal`.
+pub fn reflection_pdf(wo: Vec3A, h: Vec3A, alpha: f32) -> f32 {
+    smith_g1(wo, alpha) * distribution(h, alpha) / (4.0 * wo.z)
+}
+
+// Specular BRDF without the Fresnel term.
+pub fn specular(wo: Vec3A, wi: Vec3A, h: Vec3A, alpha: f32) -> f32 {
+    distribution(h, alpha) * smith_g1(wo, alpha) * smith_g1(wi, alpha) / (4.0 * wo.z * wi.z)
+}
+
+pub fn reflect(wo: Vec3A, h: Vec3A) -> Vec3A {
+    2.0 * wo.dot(h) * h - wo
+}
+
+// Schlick's approximation.
+pub fn fresnel_schlick(f0: Color, cos_theta: f32) -> Color {
+    f0 + (1.0 - f0) * (1.0 - cos_theta).max(0.0).powi(5)
+}
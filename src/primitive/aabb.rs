@@ -2,7 +2,7 @@ use crate::primitive::Ray;
 
 use glam::Vec3A;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Aabb {
     pub minimum: Vec3A,
     pub maximum: Vec3A
@@ -42,6 +42,14 @@ impl Aabb {
         self.maximum = self.maximum.max(v);
     }
 
+    pub fn union(self, other: Self) -> Self {
+        Self::new(self.minimum.min(other.minimum), self.maximum.max(other.maximum))
+    }
+
+    pub fn center(&self) -> Vec3A {
+        (self.minimum + self.maximum) * 0.5
+    }
+
     pub fn area(&self) -> f32 {
         let extent = self.maximum - self.minimum;
         extent.x * extent.y + extent.y * extent.z + extent.z * extent.x
@@ -42,16 +42,34 @@ impl Color {
         }
     }
 
+    pub fn max_component(self) -> f32 {
+        self.r.max(self.g).max(self.b)
+    }
+
+    pub fn powf(self, exponent: f32) -> Self {
+        Self {
+            r: self.r.powf(exponent),
+            g: self.g.powf(exponent),
+            b: self.b.powf(exponent)
+        }
+    }
+
+    // Relative luminance of linear Rec. 709 primaries.
+    pub fn luminance(self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
     pub fn into_u32(self) -> u32 {
-        ((self.r * 255.0) as u32) << 16 | ((self.g * 255.0) as u32) << 8 | ((self.b * 255.0) as u32)
+        let quantize = |c: f32| (c.clamp(0.0, 1.0) * 255.0) as u32;
+        quantize(self.r) << 16 | quantize(self.g) << 8 | quantize(self.b)
     }
 
     pub fn gamma_correct(self) -> Self {
         let correct = |c: f32| {
             if c <= 0.0031308 {
-                return 12.92 * c;
+                12.92 * c
             } else {
-                return 1.055 * (c.powf(1.0 / GAMMA)) - 0.055;
+                1.055 * (c.powf(1.0 / GAMMA)) - 0.055
             }
         };
 
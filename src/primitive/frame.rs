@@ -0,0 +1,32 @@
+use glam::Vec3A;
+
+// Orthonormal basis around a normal, used to move directions into and out of shading space.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub tangent: Vec3A,
+    pub bitangent: Vec3A,
+    pub normal: Vec3A
+}
+
+impl Frame {
+    // Branchless construction from "Building an Orthonormal Basis, Revisited" (Duff et al. 2017).
+    pub fn from_normal(normal: Vec3A) -> Self {
+        let sign = 1.0f32.copysign(normal.z);
+        let a = -1.0 / (sign + normal.z);
+        let b = normal.x * normal.y * a;
+
+        Self {
+            tangent: Vec3A::new(1.0 + sign * normal.x * normal.x * a, sign * b, -sign * normal.x),
+            bitangent: Vec3A::new(b, sign + normal.y * normal.y * a, -normal.y),
+            normal
+        }
+    }
+
+    pub fn to_local(self, v: Vec3A) -> Vec3A {
+        Vec3A::new(v.dot(self.tangent), v.dot(self.bitangent), v.dot(self.normal))
+    }
+
+    pub fn to_world(self, v: Vec3A) -> Vec3A {
+        self.tangent * v.x + self.bitangent * v.y + self.normal * v.z
+    }
+}
@@ -1,8 +1,11 @@
 pub mod ray;
-pub use ray::Ray;
+pub use ray::{Ray, RayDifferentials};
 
 pub mod color;
 pub use color::Color;
 
 pub mod aabb;
 pub use aabb::Aabb;
+
+pub mod frame;
+pub use frame::Frame;
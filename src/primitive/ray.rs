@@ -4,12 +4,32 @@ use glam::Vec3A;
 pub struct Ray {
     pub origin: Vec3A,
     pub direction: Vec3A,
-    pub dir_inv: Vec3A
+    pub dir_inv: Vec3A,
+    pub differentials: Option<RayDifferentials>,
+    // Moment within the shutter interval at which the ray travels, in seconds of animation time.
+    pub time: f32
+}
+
+// Rays offset by one pixel in x and y, used to estimate the texture footprint of a hit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayDifferentials {
+    pub rx_origin: Vec3A,
+    pub rx_direction: Vec3A,
+    pub ry_origin: Vec3A,
+    pub ry_direction: Vec3A
 }
 
 impl Ray {
     pub fn new(origin: Vec3A, direction: Vec3A) -> Self {
-        Self { origin, direction, dir_inv: direction.recip() }
+        Self { origin, direction, dir_inv: direction.recip(), differentials: None, time: 0.0 }
+    }
+
+    pub fn with_differentials(self, differentials: Option<RayDifferentials>) -> Self {
+        Self { differentials, ..self }
+    }
+
+    pub fn with_time(self, time: f32) -> Self {
+        Self { time, ..self }
     }
 
     pub fn at(&self, t: f32) -> Vec3A {
@@ -1,24 +1,30 @@
 use crate::{
-    IMAGE_WIDTH, IMAGE_HEIGHT, SAMPLES, BOUNCES,
     primitive::*,
     util::ProgressBar,
     Scene,
-    material::Scatterable
+    Camera,
+    RenderSettings,
+    material::{Material, Scatterable},
+    triangle::HitRecord,
+    light::LightSample
 };
 
+
+use glam::Vec3A;
 use rayon::prelude::*;
+use fastrand::f32;
 
 const FALLBACK_COLOR: Color = Color::rgb(1.0, 0.0, 1.0);
 
-pub fn render_scene(scene: &Scene) -> Vec<u32> {
-    let progress_bar = ProgressBar::new(IMAGE_HEIGHT);
-    let mut pixels = vec![0; IMAGE_WIDTH * IMAGE_HEIGHT];
-    let bands: Vec<(usize, &mut [u32])> = pixels.chunks_mut(IMAGE_WIDTH).enumerate().collect();
+pub fn render_scene(scene: &Scene, camera: &Camera, settings: &RenderSettings) -> Vec<u32> {
+    let progress_bar = ProgressBar::new(settings.height);
+    let mut pixels = vec![0; settings.width * settings.height];
+    let bands: Vec<(usize, &mut [u32])> = pixels.chunks_mut(settings.width).enumerate().collect();
 
     bands
         .into_par_iter()
         .for_each(|(y, band)| {
-            render_line(band, y, scene);
+            render_line(band, y, scene, camera, settings);
             progress_bar.update();
         });
 
@@ -26,36 +32,156 @@ pub fn render_scene(scene: &Scene) -> Vec<u32> {
     pixels
 }
 
-fn render_line(pixels: &mut [u32], y: usize, scene: &Scene) {
+fn render_line(pixels: &mut [u32], y: usize, scene: &Scene, camera: &Camera, settings: &RenderSettings) {
     for (x, pixel) in pixels.iter_mut().enumerate() {
         let mut color = Color::BLACK;
 
-        for _ in 0..SAMPLES {
-            let ray = scene.camera.ray_from(x, y);
-            color += trace_ray(ray, BOUNCES, &scene);
+        for _ in 0..settings.samples {
+            if let Some(ray) = camera.ray_from(x, y) {
+                color += trace_ray(ray, scene, settings);
+            }
         }
 
-        *pixel = (color / SAMPLES as f32).gamma_correct().into_u32();
+        *pixel = (color / settings.samples as f32).gamma_correct().into_u32();
     }
 }
 
-fn trace_ray(ray: Ray, depth: usize, scene: &Scene) -> Color {
-    if depth == 0 {
-        return Color::BLACK;
-    }
+fn trace_ray(mut ray: Ray, scene: &Scene, settings: &RenderSettings) -> Color {
+    let mut radiance = Color::BLACK;
+    let mut throughput = Color::WHITE;
 
-    match scene.bvh.intersects(&ray) {
-        Some(hit_record) => match hit_record.material_index {
-            Some(index) => match scene.materials[index].scatter(&ray, &hit_record, scene) {
-                (Some(reflective_ray), color) => color * trace_ray(reflective_ray, depth - 1, scene),
-                (None, color) => color,
-            },
-            _ => FALLBACK_COLOR
-        },
-        _ => {
-            // Sky gradient.
-            let a = 0.5 * (ray.direction.y + 1.0);
-            (1.0 - a) * Color::WHITE + a * Color::rgb(0.5, 0.7, 1.0)
+    // Pdf of the BSDF sample that spawned the ray, None for camera rays and specular bounces.
+    let mut bsdf_pdf: Option<f32> = None;
+    // Shading normal at the ray origin, which the light hierarchy weighs lights by.
+    let mut origin_normal = Vec3A::ZERO;
+    // Materials of the volumes the ray is inside, innermost last.
+    let mut media: Vec<usize> = Vec::new();
+
+    for depth in 0..settings.bounces {
+        let hit_record = scene.intersects(&ray);
+
+        // Absorption along the segment, inside the innermost volume.
+        if let Some(&medium) = media.last() {
+            throughput *= scene.materials[medium].transmittance(hit_record.as_ref().map_or(f32::INFINITY, |h| h.t));
         }
+
+        let Some(hit_record) = hit_record else {
+            let mut emitted = scene.environment.radiance(ray.direction);
+
+            if let Some(bsdf_pdf) = bsdf_pdf {
+                emitted *= power_heuristic(bsdf_pdf, scene.environment.pdf(ray.direction));
+            }
+
+            radiance += throughput * emitted;
+            break;
+        };
+
+        let Some(index) = hit_record.material_index else {
+            radiance += throughput * FALLBACK_COLOR;
+            break;
+        };
+
+        let material = &scene.materials[index];
+
+        let mut emitted = material.emitted(&hit_record, scene);
+
+        if let Some(bsdf_pdf) = bsdf_pdf && emitted != Color::BLACK {
+            let light_pdf = scene.lights.pdf(ray.origin, origin_normal, &hit_record, scene);
+            emitted *= power_heuristic(bsdf_pdf, light_pdf);
+        }
+
+        radiance += throughput * emitted;
+
+        // Light sampling covers the non-delta lobes of the whole material, whichever lobe the path continues with.
+        if material.has_non_delta_lobe(&hit_record, scene) {
+            radiance += throughput * sample_lights(&ray, &hit_record, material, scene);
+        }
+
+        let Some(scatter) = material.scatter(&ray, &hit_record, scene) else {
+            break;
+        };
+
+        // Rays passing through a glass surface enter or leave its volume.
+        if let Material::Glass(_) = material && scatter.ray.direction.dot(hit_record.normal) < 0.0 {
+            match hit_record.front_face {
+                true => media.push(index),
+                false => if let Some(i) = media.iter().rposition(|&medium| medium == index) {
+                    media.remove(i);
+                }
+            }
+        }
+
+        throughput *= scatter.attenuation;
+        bsdf_pdf = (!scatter.specular).then_some(scatter.pdf);
+
+        // Russian roulette: terminate dark paths early and boost surviving ones to stay unbiased.
+        if depth + 1 >= settings.russian_roulette_depth {
+            let survival = throughput.max_component().min(1.0);
+            if f32() >= survival {
+                break;
+            }
+
+            throughput /= survival;
+        }
+
+        origin_normal = hit_record.normal;
+        ray = match scatter.specular {
+            true => {
+                let differentials = specular_differentials(&ray, &hit_record, scatter.ray.direction);
+                scatter.ray.with_differentials(differentials)
+            },
+            false => scatter.ray
+        };
+    }
+
+    radiance
+}
+
+// Carries the ray differentials across a specular bounce, keeping textures in mirrors and glass filtered.
+fn specular_differentials(ray: &Ray, hit_record: &HitRecord, direction: Vec3A) -> Option<RayDifferentials> {
+    let d = ray.differentials?;
+    let [dpdx, dpdy] = hit_record.point_differentials?;
+    let normal = hit_record.normal;
+
+    let scatter_direction = |offset_direction: Vec3A| match direction.dot(normal) > 0.0 {
+        true => offset_direction.reflect(normal),
+        // Refraction keeps the angular spread of the incoming differentials.
+        false => (direction + offset_direction - ray.direction).normalize()
+    };
+
+    Some(RayDifferentials {
+        rx_origin: hit_record.point + dpdx,
+        rx_direction: scatter_direction(d.rx_direction),
+        ry_origin: hit_record.point + dpdy,
+        ry_direction: scatter_direction(d.ry_direction)
+    })
+}
+
+// Radiance reflected from a light picked by the hierarchy, the environment and every directional light.
+fn sample_lights(ray: &Ray, hit_record: &HitRecord, material: &Material, scene: &Scene) -> Color {
+    [scene.lights.sample(hit_record.point, hit_record.normal, hit_record.time, scene), scene.environment.sample()]
+        .into_iter()
+        .flatten()
+        .chain(scene.lights.directional_samples(hit_record.point))
+        .fold(Color::BLACK, |sum, light_sample| sum + light_contribution(ray, hit_record, material, &light_sample, scene))
+}
+
+fn light_contribution(ray: &Ray, hit_record: &HitRecord, material: &Material, light_sample: &LightSample, scene: &Scene) -> Color {
+    let f = material.eval(ray, hit_record, light_sample.direction, scene);
+    if f == Color::BLACK || !light_sample.is_visible(hit_record.point, hit_record.time, scene) {
+        return Color::BLACK;
     }
+
+    let weight = match light_sample.delta {
+        true => 1.0,
+        false => power_heuristic(light_sample.pdf, material.pdf(ray, hit_record, light_sample.direction, scene))
+    };
+
+    f * light_sample.radiance * (weight / light_sample.pdf)
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
 }
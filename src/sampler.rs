@@ -1,45 +1,178 @@
-use crate::{primitive::Color, Scene};
+use crate::{primitive::Color, triangle::HitRecord, Scene};
 
-use glam::Vec2;
-use gltf::image::{Data, Format};
+use std::ops::{Add, Mul};
+use glam::{Vec2, Vec3A, Vec4Swizzles};
+use gltf::{
+    image::{Data, Format},
+    texture::{MagFilter, MinFilter, WrappingMode}
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Sampler {
     Color(Color),
-    Texture(usize)
+    Texture(TextureSampler)
+}
+
+// Texture lookup with the wrap and filter modes of a glTF sampler.
+#[derive(Debug, Clone, Copy)]
+pub struct TextureSampler {
+    pub index: usize,
+    pub wrap_s: Wrap,
+    pub wrap_t: Wrap,
+    pub filter: Filter,
+    // Whether minified lookups blend between mip levels.
+    pub mipmaps: bool
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Wrap {
+    Repeat,
+    ClampToEdge,
+    MirroredRepeat
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    // Cubic B-spline, which is smoother than bilinear and never overshoots.
+    Bicubic
 }
 
+// Tangent-space normal texture.
+#[derive(Debug, Clone, Copy)]
+pub struct NormalMap {
+    pub sampler: Sampler,
+    pub scale: f32
+}
+
+// Mip pyramid, from the full resolution image down to a single texel.
 #[derive(Debug)]
 pub struct Texture {
-    pub width: usize,
-    pub height: usize,
-    pub pixels: Vec<Color>
+    levels: Vec<MipLevel>
+}
+
+#[derive(Debug)]
+struct MipLevel {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+    // Linear alpha channel, if the image has one.
+    alpha: Option<Vec<f32>>
 }
 
 impl Sampler {
+    // Full resolution lookup, for points without a known footprint.
     pub fn sample(&self, uv: Option<Vec2>, scene: &Scene) -> Color {
+        self.sample_footprint(uv, None, scene)
+    }
+
+    // Lookup filtered over the footprint of the ray differentials at the hit point.
+    pub fn sample_at(&self, hit_record: &HitRecord, scene: &Scene) -> Color {
+        self.sample_footprint(hit_record.uv, hit_record.uv_differentials, scene)
+    }
+
+    fn sample_footprint(&self, uv: Option<Vec2>, uv_differentials: Option<[Vec2; 2]>, scene: &Scene) -> Color {
         match self {
             Sampler::Color(color) => *color,
-            Sampler::Texture(index) => scene
-                .textures[*index]
-                .sample(uv.expect("Missing uv coordinates required for texture sampling"))
+            Sampler::Texture(sampler) => scene
+                .textures[sampler.index]
+                .sample(uv.expect("Missing uv coordinates required for texture sampling"), uv_differentials, sampler)
+        }
+    }
+
+    pub fn sample_alpha(&self, uv: Option<Vec2>, scene: &Scene) -> f32 {
+        match self {
+            Sampler::Color(_) => 1.0,
+            Sampler::Texture(sampler) => scene
+                .textures[sampler.index]
+                .sample_alpha(uv.expect("Missing uv coordinates required for texture sampling"), sampler)
         }
     }
 
     pub fn texture_index(&self) -> Option<usize> {
         match self {
-            Sampler::Texture(index) => Some(*index),
+            Sampler::Texture(sampler) => Some(sampler.index),
             Sampler::Color(_) => None,
         }
     }
 }
 
+impl TextureSampler {
+    pub fn new(texture: &gltf::Texture, bicubic: bool) -> Self {
+        let sampler = texture.sampler();
+
+        let filter = match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => Filter::Nearest,
+            _ if bicubic => Filter::Bicubic,
+            _ => Filter::Bilinear
+        };
+
+        let mipmaps = !matches!(sampler.min_filter(), Some(MinFilter::Nearest | MinFilter::Linear));
+
+        Self {
+            index: texture.source().index(),
+            wrap_s: sampler.wrap_s().into(),
+            wrap_t: sampler.wrap_t().into(),
+            filter,
+            mipmaps
+        }
+    }
+}
+
+impl From<WrappingMode> for Wrap {
+    fn from(mode: WrappingMode) -> Self {
+        match mode {
+            WrappingMode::Repeat => Wrap::Repeat,
+            WrappingMode::ClampToEdge => Wrap::ClampToEdge,
+            WrappingMode::MirroredRepeat => Wrap::MirroredRepeat
+        }
+    }
+}
+
+impl Wrap {
+    fn apply(self, i: isize, size: usize) -> usize {
+        let size = size as isize;
+        let wrapped = match self {
+            Wrap::Repeat => i.rem_euclid(size),
+            Wrap::ClampToEdge => i.clamp(0, size - 1),
+            Wrap::MirroredRepeat => {
+                let m = i.rem_euclid(2 * size);
+                if m >= size { 2 * size - 1 - m } else { m }
+            }
+        };
+
+        wrapped as usize
+    }
+}
+
+impl NormalMap {
+    // Perturbed normal at the hit point, or None if the hit carries no tangent frame.
+    pub fn shading_normal(&self, hit_record: &HitRecord, scene: &Scene) -> Option<Vec3A> {
+        let tangent = hit_record.tangent?;
+        let texel = self.sampler.sample_at(hit_record, scene);
+
+        let normal = hit_record.normal;
+        let t = Vec3A::from(tangent.xyz());
+        let b = normal.cross(t) * tangent.w;
+
+        let local = Vec3A::new(
+            (texel.r * 2.0 - 1.0) * self.scale,
+            (texel.g * 2.0 - 1.0) * self.scale,
+            texel.b * 2.0 - 1.0
+        );
+
+        (t * local.x + b * local.y + normal * local.z).try_normalize()
+    }
+}
+
 impl Texture {
     pub fn new(image_data: &Data, corrected: bool) -> Self {
-        let pixels = image_data.pixels
-            .chunks(channels_amount(image_data.format))
-            .map(|p| {
-                let mut color = Color::rgb_u8(p[0], p[1], p[2]);
+        let texels = rgba_texels(image_data);
+        let pixels = texels
+            .iter()
+            .map(|&[r, g, b, _]| {
+                let mut color = Color::rgb(r, g, b);
 
                 if corrected {
                     color = color.gamma_uncorrect();
@@ -49,25 +182,269 @@ impl Texture {
             })
             .collect();
 
-        Self {
+        let alpha = has_alpha(image_data.format).then(|| texels.iter().map(|texel| texel[3]).collect());
+
+        let mut levels = vec![MipLevel {
             width: image_data.width as usize,
             height: image_data.height as usize,
-            pixels
+            pixels,
+            alpha
+        }];
+
+        while let Some(level) = levels.last().and_then(MipLevel::downsample) {
+            levels.push(level);
         }
+
+        Self { levels }
     }
 
-    pub fn sample(&self, uv: Vec2) -> Color {
-        let x = (uv.x.fract() * (self.width - 1) as f32).round() as usize;
-        let y = (uv.y.fract() * (self.height - 1) as f32).round() as usize;
+    pub fn sample(&self, uv: Vec2, uv_differentials: Option<[Vec2; 2]>, sampler: &TextureSampler) -> Color {
+        self.sample_levels(uv, uv_differentials, sampler, |level, uv| level.filter(uv, sampler, |i| level.pixels[i]))
+    }
 
-        self.pixels[y * self.width + x]
+    pub fn sample_alpha(&self, uv: Vec2, sampler: &TextureSampler) -> f32 {
+        let level = &self.levels[0];
+        match &level.alpha {
+            Some(alpha) => level.filter(uv, sampler, |i| alpha[i]),
+            None => 1.0
+        }
     }
+
+    // Anisotropic trilinear filtering along the major axis of the footprint.
+    fn sample_levels(
+        &self,
+        uv: Vec2,
+        uv_differentials: Option<[Vec2; 2]>,
+        sampler: &TextureSampler,
+        lookup: impl Fn(&MipLevel, Vec2) -> Color
+    ) -> Color {
+        const MAX_ANISOTROPY: f32 = 8.0;
+
+        let Some([duv_dx, duv_dy]) = uv_differentials.filter(|[dx, dy]| sampler.mipmaps && dx.is_finite() && dy.is_finite()) else {
+            return lookup(&self.levels[0], uv);
+        };
+
+        let size = Vec2::new(self.levels[0].width as f32, self.levels[0].height as f32);
+        let (mut major, mut minor) = (duv_dx, duv_dy);
+        if (minor * size).length_squared() > (major * size).length_squared() {
+            std::mem::swap(&mut major, &mut minor);
+        }
+
+        let major_length = (major * size).length();
+        let minor_length = (minor * size).length();
+        let taps = (major_length / minor_length.max(1e-8)).clamp(1.0, MAX_ANISOTROPY).ceil();
+
+        let lod = (major_length / taps).max(1e-8).log2().clamp(0.0, (self.levels.len() - 1) as f32);
+        let lower = lod.floor() as usize;
+        let upper = (lower + 1).min(self.levels.len() - 1);
+        let blend = lod - lower as f32;
+
+        let mut color = Color::BLACK;
+        for i in 0..taps as usize {
+            let offset = major * ((i as f32 + 0.5) / taps - 0.5);
+            let tap_uv = uv + offset;
+
+            color += lookup(&self.levels[lower], tap_uv) * (1.0 - blend);
+            if blend > 0.0 {
+                color += lookup(&self.levels[upper], tap_uv) * blend;
+            }
+        }
+
+        color / taps
+    }
+}
+
+impl MipLevel {
+    // Next smaller level by averaging 2x2 blocks, or None once a single texel is reached.
+    fn downsample(&self) -> Option<Self> {
+        if self.width == 1 && self.height == 1 {
+            return None;
+        }
+
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+
+        let average = |values: &[f32]| -> Vec<f32> {
+            self.block_indices(width, height)
+                .map(|block| block.iter().map(|&i| values[i]).sum::<f32>() * 0.25)
+                .collect()
+        };
+
+        let pixels = self.block_indices(width, height)
+            .map(|block| block.iter().fold(Color::BLACK, |sum, &i| sum + self.pixels[i]) * 0.25)
+            .collect();
+
+        Some(Self {
+            width,
+            height,
+            pixels,
+            alpha: self.alpha.as_deref().map(average)
+        })
+    }
+
+    // Indices of the four source texels of each texel of a level with the given size.
+    fn block_indices(&self, width: usize, height: usize) -> impl Iterator<Item = [usize; 4]> + '_ {
+        (0..height).flat_map(move |y| (0..width).map(move |x| {
+            let x0 = (2 * x).min(self.width - 1);
+            let x1 = (2 * x + 1).min(self.width - 1);
+            let y0 = (2 * y).min(self.height - 1) * self.width;
+            let y1 = (2 * y + 1).min(self.height - 1) * self.width;
+            [y0 + x0, y0 + x1, y1 + x0, y1 + x1]
+        }))
+    }
+
+    // Texel centers lie at half-integer coordinates.
+    fn filter<T>(&self, uv: Vec2, sampler: &TextureSampler, texel: impl Fn(usize) -> T) -> T
+    where
+        T: Copy + Add<Output = T> + Mul<f32, Output = T>
+    {
+        let fetch = |x: isize, y: isize| {
+            let x = sampler.wrap_s.apply(x, self.width);
+            let y = sampler.wrap_t.apply(y, self.height);
+            texel(y * self.width + x)
+        };
+
+        let x = uv.x * self.width as f32;
+        let y = uv.y * self.height as f32;
+
+        match sampler.filter {
+            Filter::Nearest => fetch(x.floor() as isize, y.floor() as isize),
+            Filter::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as isize, y0 as isize);
+
+                let top = fetch(x0, y0) * (1.0 - fx) + fetch(x0 + 1, y0) * fx;
+                let bottom = fetch(x0, y0 + 1) * (1.0 - fx) + fetch(x0 + 1, y0 + 1) * fx;
+                top * (1.0 - fy) + bottom * fy
+            },
+            Filter::Bicubic => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let wx = cubic_b_spline_weights(x - x0);
+                let wy = cubic_b_spline_weights(y - y0);
+                let (x0, y0) = (x0 as isize, y0 as isize);
+
+                let row = |j: usize| {
+                    let y = y0 + j as isize - 1;
+                    fetch(x0 - 1, y) * wx[0] + fetch(x0, y) * wx[1] + fetch(x0 + 1, y) * wx[2] + fetch(x0 + 2, y) * wx[3]
+                };
+
+                row(0) * wy[0] + row(1) * wy[1] + row(2) * wy[2] + row(3) * wy[3]
+            }
+        }
+    }
+}
+
+fn cubic_b_spline_weights(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    [
+        (1.0 - t).powi(3) / 6.0,
+        (3.0 * t3 - 6.0 * t2 + 4.0) / 6.0,
+        (-3.0 * t3 + 3.0 * t2 + 3.0 * t + 1.0) / 6.0,
+        t3 / 6.0
+    ]
 }
 
-fn channels_amount(format: Format) -> usize {
+// Texels as RGBA in [0, 1], with gray images spread over the color channels and opaque alpha where the image has none.
+fn rgba_texels(image_data: &Data) -> Vec<[f32; 4]> {
     use Format::*;
-    match format {
-        R8G8B8A8 | R16G16B16A16 | R32G32B32A32FLOAT => 4,
-        _ => 3
+
+    let channels = match image_data.format {
+        R8 | R16 => 1,
+        R8G8 | R16G16 => 2,
+        R8G8B8 | R16G16B16 | R32G32B32FLOAT => 3,
+        R8G8B8A8 | R16G16B16A16 | R32G32B32A32FLOAT => 4
+    };
+
+    let pixels = &image_data.pixels;
+    let values: Vec<f32> = match image_data.format {
+        R8 | R8G8 | R8G8B8 | R8G8B8A8 => pixels.iter().map(|&v| v as f32 / 255.0).collect(),
+        R16 | R16G16 | R16G16B16 | R16G16B16A16 => pixels
+            .chunks_exact(2)
+            .map(|v| u16::from_ne_bytes([v[0], v[1]]) as f32 / 65535.0)
+            .collect(),
+        R32G32B32FLOAT | R32G32B32A32FLOAT => pixels
+            .chunks_exact(4)
+            .map(|v| f32::from_ne_bytes([v[0], v[1], v[2], v[3]]))
+            .collect()
+    };
+
+    values
+        .chunks_exact(channels)
+        .map(|texel| match *texel {
+            [v] => [v, v, v, 1.0],
+            [v, a] => [v, v, v, a],
+            [r, g, b] => [r, g, b, 1.0],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!()
+        })
+        .collect()
+}
+
+fn has_alpha(format: Format) -> bool {
+    use Format::*;
+    matches!(format, R8G8 | R16G16 | R8G8B8A8 | R16G16B16A16 | R32G32B32A32FLOAT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(format: Format, width: u32, height: u32, pixels: Vec<u8>) -> Texture {
+        Texture::new(&Data { pixels, format, width, height }, false)
+    }
+
+    #[test]
+    fn repeat_wraps_negative_and_large_coordinates() {
+        assert_eq!(Wrap::Repeat.apply(-1, 4), 3);
+        assert_eq!(Wrap::Repeat.apply(-5, 4), 3);
+        assert_eq!(Wrap::Repeat.apply(4, 4), 0);
+        assert_eq!(Wrap::Repeat.apply(9, 4), 1);
+    }
+
+    #[test]
+    fn clamp_to_edge_holds_the_border_texels() {
+        assert_eq!(Wrap::ClampToEdge.apply(-3, 4), 0);
+        assert_eq!(Wrap::ClampToEdge.apply(2, 4), 2);
+        assert_eq!(Wrap::ClampToEdge.apply(7, 4), 3);
+    }
+
+    #[test]
+    fn mirrored_repeat_reflects_at_every_border() {
+        let wrapped: Vec<usize> = (-5..9).map(|i| Wrap::MirroredRepeat.apply(i, 4)).collect();
+        assert_eq!(wrapped, [3, 3, 2, 1, 0, 0, 1, 2, 3, 3, 2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn gray_r8_texels_fill_every_color_channel() {
+        let texture = texture(Format::R8, 3, 2, vec![0, 51, 102, 153, 204, 255]);
+
+        assert_eq!(texture.levels[0].pixels[1], Color::gray(0.2));
+        assert_eq!(texture.levels[0].pixels[5], Color::WHITE);
+        assert!(texture.levels[0].alpha.is_none());
+        assert_eq!(texture.levels.len(), 2);
+    }
+
+    #[test]
+    fn r16_texels_are_read_as_16_bit_values() {
+        let pixels = [0u16, 65535, 32768, 13107].iter().flat_map(|v| v.to_ne_bytes()).collect();
+        let texture = texture(Format::R16, 2, 2, pixels);
+
+        assert_eq!(texture.levels[0].pixels[1], Color::WHITE);
+        assert_eq!(texture.levels[0].pixels[3], Color::gray(0.2));
+        assert_eq!(texture.levels.len(), 2);
+        assert!((texture.levels[1].pixels[0].r - (65535.0 + 32768.0 + 13107.0) / 65535.0 / 4.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn gray_alpha_texels_keep_their_alpha() {
+        let texture = texture(Format::R8G8, 1, 1, vec![255, 51]);
+
+        assert_eq!(texture.levels[0].pixels[0], Color::WHITE);
+        assert_eq!(texture.levels[0].alpha.as_deref(), Some(&[0.2][..]));
     }
 }
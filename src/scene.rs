@@ -1,77 +1,285 @@
 use crate::{
-    IMAGE_WIDTH, IMAGE_HEIGHT,
-    triangle::{Triangle, Vertex},
+    RenderSettings,
+    CameraSelection,
+    Panorama,
+    primitive::Color,
+    triangle::{Triangle, Vertex, HitRecord},
     material, Material,
+    primitive::Ray,
+    sampler::{NormalMap, TextureSampler},
     Sampler,
     Camera,
+    camera::Lens,
     Bvh,
-    Texture
+    Lights,
+    light::{PunctualLight, PunctualKind},
+    Environment,
+    Texture,
+    animation::{Animation, Motion}
 };
 
 use std::path::Path;
 use gltf::{
     Document,
+    json,
     buffer::Data,
-    camera::Projection::Perspective,
+    camera::Projection::{Perspective, Orthographic},
+    khr_lights_punctual::Kind,
+    material::AlphaMode,
 };
-use glam::{Vec3A, Vec2, Affine3A, Mat4};
+use glam::{Vec3A, Vec2, Vec4, Vec4Swizzles, Affine3A};
+
+// Index of refraction used by glTF when KHR_materials_ior is absent.
+const DEFAULT_IOR: f32 = 1.5;
+
+const DEFAULT_ALPHA_CUTOFF: f32 = 0.5;
 
 #[derive(Debug)]
 pub struct Scene {
     pub name: String,
-    pub camera: Camera,
+    // Camera nodes in file order.
+    pub cameras: Vec<Camera>,
     pub bvh: Bvh,
+    pub lights: Lights,
+    pub environment: Environment,
     pub materials: Vec<Material>,
-    pub textures: Vec<Texture>
+    pub textures: Vec<Texture>,
+    // Kept to pose the scene again at other animation times.
+    gltf: Document,
+    buffers: Vec<Data>,
+    animation: Animation
 }
 
 impl Scene {
-    pub fn import(path: &Path) -> Self {
-        let (gltf, buffers, images) = gltf::import(path).unwrap();
+    pub fn import(path: &Path, settings: &RenderSettings) -> Result<Self, String> {
+        let (gltf, buffers, images) = gltf::import(path).map_err(|e| format!("Cannot open scene '{}': {}", path.display(), e))?;
+        let environment = Environment::new(settings)?;
 
-        let materials = import_materials(&gltf);
+        let materials = import_materials(&gltf, settings);
         let textures = import_textures(&images, &materials);
 
-        let triangles = import_triangles(&gltf, &buffers);
+        let animation = Animation::new(&gltf, &buffers);
+        let (bvh, lights, cameras) = pose(&gltf, &buffers, &animation, &materials, settings);
 
-        Scene {
+        Ok(Scene {
             name: path.file_stem().map_or("image", |s| s.to_str().unwrap()).to_owned(),
-            camera: import_camera(&gltf),
-            bvh: Bvh::new(triangles),
+            cameras,
+            bvh,
+            lights,
+            environment,
             materials,
-            textures
+            textures,
+            gltf,
+            buffers,
+            animation
+        })
+    }
+
+    // Moves the animated nodes to `settings.time`, rebuilding the BVH, lights and cameras.
+    pub fn pose(&mut self, settings: &RenderSettings) {
+        (self.bvh, self.lights, self.cameras) = pose(&self.gltf, &self.buffers, &self.animation, &self.materials, settings);
+    }
+
+    // Time of the last keyframe of the animations, in seconds.
+    pub fn animation_duration(&self) -> f32 {
+        self.animation.duration()
+    }
+
+    // Cameras picked by the selection, or an error naming what could not be found.
+    pub fn selected_cameras(&self, selection: &CameraSelection) -> Result<Vec<&Camera>, String> {
+        let names: Vec<String> = self.cameras.iter().map(|camera| camera.name.clone()).collect();
+
+        Ok(selection
+            .indices(&names)?
+            .into_iter()
+            .map(|index| &self.cameras[index])
+            .collect())
+    }
+
+    // Closest hit along the ray, with the shading normal perturbed by the material's normal map.
+    pub fn intersects(&self, ray: &Ray) -> Option<HitRecord> {
+        let mut hit_record = self.bvh.intersects(ray, |tri, t| self.is_opaque_hit(tri, ray, t))?;
+
+        let normal_map = hit_record.material_index.and_then(|i| self.materials[i].normal_map());
+        if let Some(normal) = normal_map.and_then(|map| map.shading_normal(&hit_record, self)) {
+            // Normals facing away from the viewer would make the hit invisible.
+            if normal.dot(ray.direction) < 0.0 {
+                hit_record.normal = normal;
+            }
+        }
+
+        Some(hit_record)
+    }
+
+    // Whether any surface blocks the ray before the given distance.
+    pub fn occluded(&self, ray: &Ray, distance: f32) -> bool {
+        self.bvh.occluded(ray, distance, |tri, t| self.is_opaque_hit(tri, ray, t))
+    }
+
+    // Alpha test of masked and blended materials.
+    fn is_opaque_hit(&self, triangle: &Triangle, ray: &Ray, t: f32) -> bool {
+        match triangle.material_index.and_then(|i| self.materials[i].alpha()) {
+            Some(alpha) => alpha.is_opaque(triangle.uv_at(ray.at(t)), self),
+            None => true
         }
     }
 }
 
-fn import_camera(gltf: &Document) -> Camera {
+// Geometry, lights and cameras with the node transforms at the settings' time.
+fn pose(
+    gltf: &Document,
+    buffers: &[Data],
+    animation: &Animation,
+    materials: &[Material],
+    settings: &RenderSettings
+) -> (Bvh, Lights, Vec<Camera>) {
+    let (triangles, motions) = import_triangles(gltf, buffers, animation, settings);
+    let bvh = Bvh::new(triangles, motions);
+    let lights = Lights::new(&bvh.triangles, materials, import_punctual_lights(gltf, animation, settings));
+    let cameras = import_cameras(gltf, &bvh, animation, settings);
+
+    (bvh, lights, cameras)
+}
+
+// Names of all camera nodes in the scene, in the order used for camera indices.
+pub fn list_cameras(path: &Path) -> Result<Vec<String>, String> {
+    let gltf = gltf::Gltf::open(path).map_err(|e| format!("Cannot open scene '{}': {}", path.display(), e))?;
+
+    Ok(camera_nodes(&gltf)
+        .map(|(name, _, _)| name)
+        .collect())
+}
+
+// Camera nodes with the name they are selected by: the node name, else the camera name, else their index.
+fn camera_nodes(gltf: &Document) -> impl Iterator<Item = (String, gltf::Node<'_>, gltf::Camera<'_>)> {
     gltf
         .nodes()
-        .find_map(|node| node.camera().map(|cam| {
-            let Perspective(persp) = cam.projection() else {
-                panic!("Orthographic camera not supported");
+        .filter_map(|node| node.camera().map(|cam| (node, cam)))
+        .enumerate()
+        .map(|(i, (node, cam))| {
+            let name = node.name().or(cam.name()).map_or_else(|| format!("Camera {}", i), str::to_owned);
+            (name, node, cam)
+        })
+}
+
+fn import_cameras(gltf: &Document, bvh: &Bvh, animation: &Animation, settings: &RenderSettings) -> Vec<Camera> {
+    camera_nodes(gltf)
+        .map(|(name, node, cam)| {
+            let transform = animation.world_transform(node.index(), settings.time);
+            let motion = animation.motion(node.index(), settings.time, settings.time + settings.shutter);
+
+            let extra = |key: &str| camera_extra(&cam, &node, key);
+            let panorama = settings.panorama.or_else(|| extra("panorama")
+                .and_then(|value| value.as_str().and_then(Panorama::from_name)));
+
+            let camera = match (panorama, cam.projection()) {
+                (Some(Panorama::Equirectangular), _) => Camera::equirectangular(name, transform, settings),
+                (Some(Panorama::Fisheye), _) => {
+                    let fov = settings.fisheye_fov
+                        .or_else(|| extra("fisheyeFov").and_then(|value| value.as_f64()).map(|fov| fov as f32))
+                        .unwrap_or(180.0);
+                    Camera::fisheye(name, fov.to_radians(), transform, settings)
+                },
+                (None, Perspective(persp)) => {
+                    let aspect_ratio = persp.aspect_ratio().unwrap_or(settings.aspect_ratio());
+                    let lens = import_lens(&cam, &node, transform, bvh, aspect_ratio, persp.yfov(), settings);
+                    Camera::perspective(name, aspect_ratio, persp.yfov(), lens, transform, settings)
+                },
+                (None, Orthographic(ortho)) => Camera::orthographic(name, ortho.xmag(), ortho.ymag(), transform, settings)
             };
 
-            let aspect_ratio = persp.aspect_ratio().unwrap_or((IMAGE_WIDTH as f32) / (IMAGE_HEIGHT as f32));
-            let transform = get_node_transform(&node);
+            camera.with_motion(motion)
+        })
+        .collect()
+}
+
+// Depth of field from the settings, or else from the camera extras.
+fn import_lens(
+    camera: &gltf::Camera,
+    node: &gltf::Node,
+    transform: Affine3A,
+    bvh: &Bvh,
+    aspect_ratio: f32,
+    y_fov: f32,
+    settings: &RenderSettings
+) -> Lens {
+    let extra = |key: &str| camera_extra(camera, node, key)
+        .and_then(|value| value.as_f64())
+        .map(|value| value as f32);
+
+    let lens = &settings.lens;
+    let (aperture_radius, f_stop) = match lens.aperture_radius.is_some() || lens.f_stop.is_some() {
+        true => (lens.aperture_radius, lens.f_stop),
+        false => (extra("apertureRadius"), extra("fStop"))
+    };
+
+    let radius = match (aperture_radius, f_stop) {
+        (Some(radius), _) => radius,
+        (None, Some(f_stop)) => Lens::aperture_radius(f_stop, y_fov, aspect_ratio),
+        (None, None) => return Lens::PINHOLE
+    };
+
+    let focus_distance = lens.focus_distance.or_else(|| extra("focusDistance")).unwrap_or_else(|| {
+        // Focuses on the surface at the image center, converting the distance into the scale of the camera node.
+        let forward = transform.transform_vector3a(Vec3A::NEG_Z);
+        let ray = Ray::new(transform.translation, forward.normalize());
+        bvh.intersects(&ray, |_, _| true).map_or(f32::INFINITY, |hit| hit.t / forward.length())
+    });
+
+    Lens { radius, focus_distance }
+}
+
+// Custom property of the camera, or else of its node.
+fn camera_extra(camera: &gltf::Camera, node: &gltf::Node, key: &str) -> Option<json::Value> {
+    [camera.extras(), node.extras()]
+        .into_iter()
+        .flatten()
+        .filter_map(|raw| json::deserialize::from_str::<json::Value>(raw.get()).ok())
+        .find_map(|value| value.get(key).cloned())
+}
+
+fn import_punctual_lights(gltf: &Document, animation: &Animation, settings: &RenderSettings) -> Vec<PunctualLight> {
+    gltf
+        .nodes()
+        .filter_map(|node| node.light().map(|light| {
+            let transform = animation.world_transform(node.index(), settings.time);
 
-            Camera::new(
-                aspect_ratio,
-                persp.yfov(),
-                transform
-            )
+            let kind = match light.kind() {
+                Kind::Point => PunctualKind::Point,
+                Kind::Directional => PunctualKind::Directional,
+                Kind::Spot { inner_cone_angle, outer_cone_angle } => PunctualKind::Spot {
+                    cos_inner: inner_cone_angle.cos(),
+                    cos_outer: outer_cone_angle.cos()
+                }
+            };
+
+            PunctualLight {
+                kind,
+                intensity: Color::from(light.color()) * light.intensity(),
+                position: transform.translation,
+                direction: transform.transform_vector3a(Vec3A::NEG_Z).normalize(),
+                range: light.range()
+            }
         }))
-        .expect("Cannot import camera")
+        .collect()
 }
 
-fn import_triangles(gltf: &Document, buffers: &Vec<Data>) -> Vec<Triangle> {
+// Triangles posed at the time the shutter opens, and the motions of those that move while it is open.
+fn import_triangles(gltf: &Document, buffers: &[Data], animation: &Animation, settings: &RenderSettings) -> (Vec<Triangle>, Vec<Motion>) {
     let mut triangles: Vec<Triangle> = Vec::new();
+    let mut motions: Vec<Motion> = Vec::new();
 
     for node in gltf.nodes() {
         let Some(mesh) = node.mesh() else {
             continue;
         };
-        let transform = get_node_transform(&node);
+        let transform = animation.world_transform(node.index(), settings.time);
+        let motion_index = animation
+            .motion(node.index(), settings.time, settings.time + settings.shutter)
+            .map(|motion| {
+                motions.push(motion);
+                motions.len() - 1
+            });
 
         for primitive in mesh.primitives() {
             let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
@@ -91,6 +299,13 @@ fn import_triangles(gltf: &Document, buffers: &Vec<Data>) -> Vec<Triangle> {
                 .map(|i| i as usize)
                 .collect();
 
+            let has_normal_map = primitive.material().normal_texture().is_some();
+            let tangents: Option<Vec<Vec4>> = match (reader.read_tangents(), &uvs) {
+                (Some(tangents), _) => Some(tangents.map(|a| a.into()).collect()),
+                (None, Some(uvs)) if has_normal_map => Some(generate_tangents(&positions, &normals, uvs, &indices)),
+                _ => None
+            };
+
             let triangle_amount = indices.len() / 3;
             triangles.reserve(triangle_amount);
 
@@ -101,8 +316,12 @@ fn import_triangles(gltf: &Document, buffers: &Vec<Data>) -> Vec<Triangle> {
 
                 let position = transform.transform_point3a(positions[idx]);
                 let normal = transform.transform_vector3a(normals[idx]).normalize();
+                let tangent = tangents.as_ref().map(|tangents| {
+                    let t = tangents[idx];
+                    transform.transform_vector3a(Vec3A::from(t.xyz())).normalize().extend(t.w)
+                });
 
-                Vertex::new(position, normal, uvs.as_ref().map(|uv| uv[idx]))
+                Vertex::new(position, normal, uvs.as_ref().map(|uv| uv[idx]), tangent)
             };
 
             for i in (0..indices.len()).step_by(3) {
@@ -113,46 +332,144 @@ fn import_triangles(gltf: &Document, buffers: &Vec<Data>) -> Vec<Triangle> {
                     material_index,
                 );
 
-                triangles.push(triangle);
+                triangles.push(match motion_index {
+                    Some(index) => triangle.with_motion(index, &motions[index]),
+                    None => triangle
+                });
             }
         }
     }
 
-    triangles
+    (triangles, motions)
 }
 
-fn import_materials(gltf: &Document) -> Vec<Material> {
+// Per-vertex tangents accumulated from the UV gradients of adjacent triangles, for meshes exported without them.
+fn generate_tangents(positions: &[Vec3A], normals: &[Vec3A], uvs: &[Vec2], indices: &[usize]) -> Vec<Vec4> {
+    let mut tangents = vec![Vec3A::ZERO; positions.len()];
+    let mut bitangents = vec![Vec3A::ZERO; positions.len()];
+
+    for tri in indices.chunks_exact(3) {
+        let (i1, i2, i3) = (tri[0], tri[1], tri[2]);
+
+        let e1 = positions[i2] - positions[i1];
+        let e2 = positions[i3] - positions[i1];
+        let d1 = uvs[i2] - uvs[i1];
+        let d2 = uvs[i3] - uvs[i1];
+
+        let det = d1.x * d2.y - d2.x * d1.y;
+        if det.abs() < f32::EPSILON {
+            continue;
+        }
+
+        let r = det.recip();
+        let tangent = (e1 * d2.y - e2 * d1.y) * r;
+        let bitangent = (e2 * d1.x - e1 * d2.x) * r;
+
+        for &i in tri {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    normals
+        .iter()
+        .zip(tangents.iter().zip(&bitangents))
+        .map(|(&n, (&t, &b))| {
+            let t = t.reject_from_normalized(n).normalize_or(n.any_orthonormal_vector());
+            let handedness = if n.cross(t).dot(b) < 0.0 { -1.0 } else { 1.0 };
+            t.extend(handedness)
+        })
+        .collect()
+}
+
+fn import_materials(gltf: &Document, settings: &RenderSettings) -> Vec<Material> {
     gltf
         .materials()
         .map(|material| {
             let pbr = material.pbr_metallic_roughness();
             let color_sampler = match pbr.base_color_texture() {
-                Some(texture_info) => Sampler::Texture(texture_info.texture().index()),
+                Some(texture_info) => texture_sampler(&texture_info.texture(), settings),
                 _ => Sampler::Color(pbr.base_color_factor().into())
             };
 
-            if let Some(_) = material.transmission() {
+            let alpha = match material.alpha_mode() {
+                AlphaMode::Opaque => None,
+                mode => Some(material::Alpha {
+                    mode: match mode {
+                        AlphaMode::Mask => material::AlphaMode::Mask(material.alpha_cutoff().unwrap_or(DEFAULT_ALPHA_CUTOFF)),
+                        _ => material::AlphaMode::Blend
+                    },
+                    factor: pbr.base_color_factor()[3],
+                    sampler: color_sampler
+                })
+            };
+
+            let normal_map = material.normal_texture().map(|normal_texture| NormalMap {
+                sampler: texture_sampler(&normal_texture.texture(), settings),
+                scale: normal_texture.scale()
+            });
+
+            let metallic_roughness_texture = pbr.metallic_roughness_texture();
+            let metallic = pbr.metallic_factor();
+            let roughness = pbr.roughness_factor();
+
+            let surface = material::Pbr {
+                color_sampler,
+                metallic_roughness_sampler: match &metallic_roughness_texture {
+                    Some(texture_info) => texture_sampler(&texture_info.texture(), settings),
+                    _ => Sampler::Color(Color::WHITE)
+                },
+                metallic_factor: metallic,
+                roughness_factor: roughness,
+                ior: material.ior().unwrap_or(DEFAULT_IOR),
+                normal_map,
+                alpha
+            };
+
+            if let Some(transmission) = material.transmission() {
+                // A zero thickness, the default, marks the material as thin-walled, with no volume to absorb light.
+                let volume = material.volume().filter(|v| v.thickness_factor() > 0.0);
+
                 Material::Glass(material::Glass {
-                    color_sampler
+                    ior: surface.ior,
+                    transmission_sampler: match transmission.transmission_texture() {
+                        Some(texture_info) => texture_sampler(&texture_info.texture(), settings),
+                        _ => Sampler::Color(Color::WHITE)
+                    },
+                    transmission_factor: transmission.transmission_factor(),
+                    attenuation_color: volume.as_ref().map_or(Color::WHITE, |v| v.attenuation_color().into()),
+                    attenuation_distance: volume.as_ref().map_or(f32::INFINITY, |v| v.attenuation_distance()),
+                    surface
                 })
             } else if material.emissive_factor().iter().any(|v| *v > 0.0) {
                 Material::Emissive(material::Emissive {
-                    color: material.emissive_factor().into()
+                    emission_sampler: match material.emissive_texture() {
+                        Some(texture_info) => texture_sampler(&texture_info.texture(), settings),
+                        _ => Sampler::Color(Color::WHITE)
+                    },
+                    factor: material.emissive_factor().into(),
+                    strength: material.emissive_strength().unwrap_or(1.0),
+                    alpha
                 })
-            } else if pbr.metallic_factor() < 1.0 {
-                Material::Diffuse(material::Diffuse {
-                    color_sampler
-                })
-            } else {
+            } else if metallic_roughness_texture.is_none() && metallic == 1.0 {
                 Material::Metal(material::Metal {
                     color_sampler,
+                    roughness_factor: roughness,
+                    normal_map,
+                    alpha
                 })
+            } else {
+                Material::Pbr(surface)
             }
         })
         .collect()
 }
 
-fn import_textures(images: &Vec<gltf::image::Data>, materials: &Vec<Material>) -> Vec<Texture> {
+fn texture_sampler(texture: &gltf::Texture, settings: &RenderSettings) -> Sampler {
+    Sampler::Texture(TextureSampler::new(texture, settings.bicubic_textures))
+}
+
+fn import_textures(images: &[gltf::image::Data], materials: &[Material]) -> Vec<Texture> {
     let color_texture_indices: Vec<usize> = materials
         .iter()
         .filter_map(|mat| mat.get_color_texture_index())
@@ -161,11 +478,6 @@ fn import_textures(images: &Vec<gltf::image::Data>, materials: &Vec<Material>) -
     images
         .iter()
         .enumerate()
-        .map(|(i, data)| Texture::new(&data, color_texture_indices.contains(&i)))
+        .map(|(i, data)| Texture::new(data, color_texture_indices.contains(&i)))
         .collect()
 }
-
-fn get_node_transform(node: &gltf::Node) -> Affine3A {
-    let transform_matrix = Mat4::from_cols_array_2d(&node.transform().matrix());
-    Affine3A::from_mat4(transform_matrix)
-}
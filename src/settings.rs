@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+
+#[cfg(debug_assertions)]
+const DEFAULT_SAMPLES: usize = 32;
+
+#[cfg(not(debug_assertions))]
+const DEFAULT_SAMPLES: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub bounces: usize,
+    // Depth after which paths are terminated by Russian roulette.
+    pub russian_roulette_depth: usize,
+    pub camera: CameraSelection,
+    // Overrides the depth of field stored in the camera extras.
+    pub lens: LensSettings,
+    // Replaces the projection of the cameras with a panoramic one.
+    pub panorama: Option<Panorama>,
+    // Field of view of fisheye cameras in degrees.
+    pub fisheye_fov: Option<f32>,
+    // Replaces bilinear texture filtering with bicubic filtering.
+    pub bicubic_textures: bool,
+    // Animation time the scene is posed at and the shutter opens, in seconds.
+    pub time: f32,
+    // How long the shutter stays open for motion blur, in seconds.
+    pub shutter: f32,
+    pub environment: EnvironmentSource,
+    // Rotation of the environment about the vertical axis, in degrees.
+    pub environment_rotation: f32,
+    pub environment_intensity: f32,
+    pub sky: SkySettings
+}
+
+// Where radiance from rays leaving the scene comes from.
+#[derive(Debug, Clone)]
+pub enum EnvironmentSource {
+    // White to light blue sky gradient.
+    Gradient,
+    // Linear RGB radiance from every direction.
+    Constant([f32; 3]),
+    // Equirectangular Radiance HDR image.
+    Image(PathBuf),
+    // Physically based daylight with a sun disk, configured by `SkySettings`.
+    Sky
+}
+
+// Which of the scene cameras to render from.
+#[derive(Debug, Clone)]
+pub enum CameraSelection {
+    // Position among the camera nodes, in file order.
+    Index(usize),
+    // Name of the camera node, or of the camera when the node is unnamed.
+    Name(String),
+    // Every camera, each to its own image.
+    All
+}
+
+// Projections covering more of the sphere than a perspective camera can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Panorama {
+    // Full sphere, with longitude across the image width and latitude across its height.
+    Equirectangular,
+    // Equidistant fisheye with a circular image, 180° wide unless set otherwise.
+    Fisheye
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LensSettings {
+    // Radius of the lens aperture in scene units.
+    pub aperture_radius: Option<f32>,
+    // Relative aperture, converted with the focal length of a full-frame camera with the same field of view.
+    pub f_stop: Option<f32>,
+    // Distance to the plane in focus. Defaults to the distance of the surface at the image center.
+    pub focus_distance: Option<f32>
+}
+
+#[derive(Debug, Clone)]
+pub struct SkySettings {
+    // Haziness of the atmosphere, from 2 (clear) to 10 (hazy).
+    pub turbidity: f32,
+    // Angle of the sun above the horizon, in degrees.
+    pub sun_elevation: f32,
+    // Angle of the sun from -z towards +x, in degrees.
+    pub sun_azimuth: f32
+}
+
+impl RenderSettings {
+    pub fn aspect_ratio(&self) -> f32 {
+        (self.width as f32) / (self.height as f32)
+    }
+}
+
+impl CameraSelection {
+    // Positions of the selected cameras among the camera names, or an error naming what could not be found.
+    pub fn indices(&self, names: &[String]) -> Result<Vec<usize>, String> {
+        match self {
+            CameraSelection::Index(index) => match *index < names.len() {
+                true => Ok(vec![*index]),
+                false => Err(format!("Scene has {} cameras, no camera with index {}", names.len(), index))
+            },
+            CameraSelection::Name(name) => names
+                .iter()
+                .position(|other| other == name)
+                .map(|index| vec![index])
+                .ok_or_else(|| format!("Scene has no camera named '{}'", name)),
+            CameraSelection::All => match names.is_empty() {
+                true => Err("Scene has no cameras".to_owned()),
+                false => Ok((0..names.len()).collect())
+            }
+        }
+    }
+}
+
+impl Panorama {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "equirectangular" => Some(Self::Equirectangular),
+            "fisheye" => Some(Self::Fisheye),
+            _ => None
+        }
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 1024,
+            height: 1024,
+            samples: DEFAULT_SAMPLES,
+            bounces: 6,
+            russian_roulette_depth: 3,
+            camera: CameraSelection::Index(0),
+            lens: LensSettings::default(),
+            panorama: None,
+            fisheye_fov: None,
+            bicubic_textures: false,
+            time: 0.0,
+            shutter: 0.0,
+            environment: EnvironmentSource::Gradient,
+            environment_rotation: 0.0,
+            environment_intensity: 1.0,
+            sky: SkySettings {
+                turbidity: 3.0,
+                sun_elevation: 45.0,
+                sun_azimuth: 0.0
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// Analytic daylight model from "A Practical Analytic Model for Daylight" (Preetham et al. 1999).
+
+use crate::{
+    primitive::{Color, Frame},
+    settings::SkySettings
+};
+
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+use glam::Vec3A;
+use fastrand::f32;
+
+// Converts luminance in kcd/m² to scene radiance, acting as the exposure of the sky.
+const LUMINANCE_SCALE: f32 = 0.02;
+
+// Luminance of the sun outside the atmosphere in kcd/m².
+const SUN_LUMINANCE: f32 = 2.0e6;
+
+// Half the apparent diameter of the sun.
+const SUN_ANGULAR_RADIUS: f32 = 0.2665 * PI / 180.0;
+
+#[derive(Debug)]
+pub struct PhysicalSky {
+    sun_direction: Vec3A,
+    cos_sun_radius: f32,
+    sun_radiance: Color,
+    // Perez distributions of luminance and the two chromaticity coordinates.
+    luminance: Perez,
+    x: Perez,
+    y: Perez
+}
+
+// Perez sky distribution scaled to the zenith value.
+#[derive(Debug)]
+struct Perez {
+    coefficients: [f32; 5],
+    // Zenith value divided by the distribution at the zenith.
+    scale: f32
+}
+
+impl PhysicalSky {
+    pub fn new(settings: &SkySettings) -> Self {
+        // The fit is only valid for moderately clear to hazy skies and suns above the horizon.
+        let turbidity = settings.turbidity.clamp(1.7, 10.0);
+        let theta_sun = (FRAC_PI_2 - settings.sun_elevation.to_radians()).clamp(0.0, FRAC_PI_2);
+
+        // Azimuth is measured from -z towards +x, matching the center of equirectangular maps.
+        let azimuth = settings.sun_azimuth.to_radians();
+        let sun_direction = Vec3A::new(
+            theta_sun.sin() * azimuth.sin(),
+            theta_sun.cos(),
+            -theta_sun.sin() * azimuth.cos()
+        );
+
+        let t = turbidity;
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let theta = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+        let zenith_chromaticity = |m: [[f32; 4]; 3]| {
+            let row = |r: [f32; 4]| r.iter().zip(theta).map(|(a, b)| a * b).sum::<f32>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+
+        let zenith_x = zenith_chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886]
+        ]);
+        let zenith_y = zenith_chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688]
+        ]);
+
+        let luminance = Perez::new([
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703
+        ], zenith_luminance, theta_sun);
+        let x = Perez::new([
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452
+        ], zenith_x, theta_sun);
+        let y = Perez::new([
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529
+        ], zenith_y, theta_sun);
+
+        Self {
+            sun_direction,
+            cos_sun_radius: SUN_ANGULAR_RADIUS.cos(),
+            sun_radiance: sun_transmittance(theta_sun, turbidity) * (SUN_LUMINANCE * LUMINANCE_SCALE),
+            luminance,
+            x,
+            y
+        }
+    }
+
+    pub fn radiance(&self, direction: Vec3A) -> Color {
+        // Below the horizon the sky continues with its horizon color.
+        let cos_theta = direction.y.max(1e-3);
+        let cos_gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0);
+
+        let luminance = self.luminance.eval(cos_theta, cos_gamma);
+        let x = self.x.eval(cos_theta, cos_gamma);
+        let y = self.y.eval(cos_theta, cos_gamma);
+        let sky = xyy_to_rgb(x, y, luminance) * LUMINANCE_SCALE;
+
+        match direction.y > 0.0 && cos_gamma >= self.cos_sun_radius {
+            true => sky + self.sun_radiance,
+            false => sky
+        }
+    }
+
+    // Uniformly distributed direction within the sun disk and its solid angle density.
+    pub fn sample_sun(&self) -> (Vec3A, f32) {
+        let cos_theta = 1.0 - f32() * (1.0 - self.cos_sun_radius);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = TAU * f32();
+
+        let local = Vec3A::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        (Frame::from_normal(self.sun_direction).to_world(local), self.sun_pdf())
+    }
+
+    // Solid angle density with which `sample_sun` chooses the direction.
+    pub fn pdf(&self, direction: Vec3A) -> f32 {
+        match direction.dot(self.sun_direction) >= self.cos_sun_radius {
+            true => self.sun_pdf(),
+            false => 0.0
+        }
+    }
+
+    fn sun_pdf(&self) -> f32 {
+        1.0 / (TAU * (1.0 - self.cos_sun_radius))
+    }
+}
+
+impl Perez {
+    fn new(coefficients: [f32; 5], zenith: f32, theta_sun: f32) -> Self {
+        let distribution = Self { coefficients, scale: 1.0 };
+        let scale = zenith / distribution.eval(1.0, theta_sun.cos());
+
+        Self { coefficients, scale }
+    }
+
+    fn eval(&self, cos_theta: f32, cos_gamma: f32) -> f32 {
+        let [a, b, c, d, e] = self.coefficients;
+        let gamma = cos_gamma.acos();
+
+        let f = (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma);
+        f * self.scale
+    }
+}
+
+// Fraction of sunlight reaching the ground after Rayleigh and aerosol scattering, at 650, 570 and 475 nm.
+fn sun_transmittance(theta_sun: f32, turbidity: f32) -> Color {
+    // Relative optical air mass, which stays finite at the horizon.
+    let air_mass = 1.0 / (theta_sun.cos() + 0.15 * (93.885 - theta_sun.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+
+    let transmittance = |wavelength: f32| {
+        let rayleigh = (-0.008735 * wavelength.powf(-4.08) * air_mass).exp();
+        let aerosol = (-beta * wavelength.powf(-1.3) * air_mass).exp();
+        rayleigh * aerosol
+    };
+
+    Color::rgb(transmittance(0.650), transmittance(0.570), transmittance(0.475))
+}
+
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Color {
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+
+    Color::rgb(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0)
+    )
+}
@@ -1,21 +1,30 @@
-use crate::primitive::*;
+use crate::{
+    primitive::*,
+    animation::Motion
+};
 
-use glam::{Vec3A, Vec2};
+use glam::{Vec3A, Vec2, Vec4, Vec4Swizzles, Affine3A};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Triangle {
     pub v1: Vertex,
     pub v2: Vertex,
     pub v3: Vertex,
     pub centroid: Vec3A,
+    // Bounds of the triangle over the whole shutter interval.
+    pub bounds: Aabb,
     pub material_index: Option<usize>,
+    // Index of the motion of moving triangles, whose vertices are posed at the start of the shutter.
+    pub motion: Option<usize>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Vertex {
     pub position: Vec3A,
     pub normal: Vec3A,
     pub uv: Option<Vec2>,
+    // Tangent direction with the bitangent handedness in w.
+    pub tangent: Option<Vec4>,
 }
 
 #[derive(Debug)]
@@ -24,19 +33,62 @@ pub struct HitRecord {
     pub point: Vec3A,
     pub normal: Vec3A,
     pub uv: Option<Vec2>,
+    pub tangent: Option<Vec4>,
+    // Offsets to the points hit by the ray differentials, and the matching texture coordinate offsets.
+    pub point_differentials: Option<[Vec3A; 2]>,
+    pub uv_differentials: Option<[Vec2; 2]>,
     pub front_face: bool,
     pub material_index: Option<usize>,
+    pub triangle_index: usize,
+    // Time of the ray that hit, which rays leaving the hit continue at.
+    pub time: f32,
 }
 
 impl Triangle {
     pub fn new(v1: Vertex, v2: Vertex, v3: Vertex, material_index: Option<usize>) -> Self {
         let centroid = (v1.position + v2.position + v3.position) * 0.3333333;
+        let mut bounds = Aabb::MAX;
+        bounds.grow(v1.position);
+        bounds.grow(v2.position);
+        bounds.grow(v3.position);
+
         Self {
             v1,
             v2,
             v3,
             centroid,
-            material_index
+            bounds,
+            material_index,
+            motion: None
+        }
+    }
+
+    // Moves along with the motion, growing the bounds to cover every pose over the shutter.
+    pub fn with_motion(self, index: usize, motion: &Motion) -> Self {
+        let mut bounds = self.bounds;
+        for time in motion.sweep_times() {
+            let transform = motion.relative(time);
+            bounds.grow(transform.transform_point3a(self.v1.position));
+            bounds.grow(transform.transform_point3a(self.v2.position));
+            bounds.grow(transform.transform_point3a(self.v3.position));
+        }
+
+        Self { bounds, motion: Some(index), ..self }
+    }
+
+    // Copy of the triangle posed by the transform.
+    pub fn transformed(&self, transform: Affine3A) -> Self {
+        let vertex = |v: &Vertex| Vertex::new(
+            transform.transform_point3a(v.position),
+            transform.transform_vector3a(v.normal).normalize(),
+            v.uv,
+            v.tangent.map(|t| transform.transform_vector3a(Vec3A::from(t.xyz())).normalize().extend(t.w))
+        );
+
+        Self {
+            motion: self.motion,
+            bounds: self.bounds,
+            ..Self::new(vertex(&self.v1), vertex(&self.v2), vertex(&self.v3), self.material_index)
         }
     }
 
@@ -53,7 +105,7 @@ impl Triangle {
         let inv_det = det.recip();
         let s = ray.origin - self.v1.position;
         let u = inv_det * s.dot(ray_cross_e2);
-        if u < 0.0 || u > 1.0 {
+        if !(0.0..=1.0).contains(&u) {
             return None;
         }
 
@@ -71,12 +123,36 @@ impl Triangle {
         }
     }
 
-    pub fn create_record(&self, ray: &Ray, t: f32) -> HitRecord {
+    pub fn create_record(&self, ray: &Ray, t: f32, triangle_index: usize) -> HitRecord {
         let point = ray.at(t);
         let barycentric = self.get_barycentric_coordinates(point);
 
         let uv = self.v1.uv.map(|uv1| uv1 * barycentric.x + self.v2.uv.unwrap() * barycentric.y + self.v3.uv.unwrap() * barycentric.z);
         let mut normal = (self.v1.normal * barycentric.x + self.v2.normal * barycentric.y + self.v3.normal * barycentric.z).normalize();
+        let mut tangent = self.v1.tangent.map(|t1| {
+            let t2 = self.v2.tangent.unwrap();
+            let t3 = self.v3.tangent.unwrap();
+            let direction = Vec3A::from(t1.xyz() * barycentric.x + t2.xyz() * barycentric.y + t3.xyz() * barycentric.z);
+
+            // Gram-Schmidt against the interpolated normal.
+            direction.reject_from_normalized(normal).normalize_or_zero().extend(t1.w)
+        });
+
+        let point_differentials = ray.differentials.and_then(|d| {
+            // Intersects the offset rays with the plane of the triangle.
+            let plane_normal = self.geometric_normal();
+            let offset = |origin: Vec3A, direction: Vec3A| {
+                let t = plane_normal.dot(point - origin) / plane_normal.dot(direction);
+                t.is_finite().then(|| origin + t * direction - point)
+            };
+
+            Some([offset(d.rx_origin, d.rx_direction)?, offset(d.ry_origin, d.ry_direction)?])
+        });
+
+        let uv_differentials = uv.zip(point_differentials).map(|(uv, [dpdx, dpdy])| [
+            self.uv_at(point + dpdx).unwrap() - uv,
+            self.uv_at(point + dpdy).unwrap() - uv
+        ]);
 
         let mut front_face = true;
 
@@ -84,6 +160,9 @@ impl Triangle {
         if ray.direction.dot(normal) > 0.0 {
             normal = -normal;
             front_face = false;
+
+            // Keeps the shading frame consistent with the flipped normal.
+            tangent = tangent.map(|t| -t);
         }
 
         HitRecord {
@@ -92,10 +171,44 @@ impl Triangle {
             normal,
             front_face,
             uv,
-            material_index: self.material_index
+            tangent,
+            point_differentials,
+            uv_differentials,
+            material_index: self.material_index,
+            triangle_index,
+            time: ray.time
         }
     }
 
+    pub fn uv_at(&self, point: Vec3A) -> Option<Vec2> {
+        let barycentric = self.get_barycentric_coordinates(point);
+        self.v1.uv.map(|uv1| uv1 * barycentric.x + self.v2.uv.unwrap() * barycentric.y + self.v3.uv.unwrap() * barycentric.z)
+    }
+
+    pub fn area(&self) -> f32 {
+        (self.v2.position - self.v1.position)
+            .cross(self.v3.position - self.v1.position)
+            .length() * 0.5
+    }
+
+    pub fn geometric_normal(&self) -> Vec3A {
+        (self.v2.position - self.v1.position)
+            .cross(self.v3.position - self.v1.position)
+            .normalize()
+    }
+
+    // Uniformly distributed point and its texture coordinates from two random numbers in [0, 1).
+    pub fn sample_point(&self, u1: f32, u2: f32) -> (Vec3A, Option<Vec2>) {
+        let su1 = u1.sqrt();
+        let b1 = 1.0 - su1;
+        let b2 = u2 * su1;
+        let b3 = 1.0 - b1 - b2;
+
+        let point = self.v1.position * b1 + self.v2.position * b2 + self.v3.position * b3;
+        let uv = self.v1.uv.map(|uv1| uv1 * b1 + self.v2.uv.unwrap() * b2 + self.v3.uv.unwrap() * b3);
+        (point, uv)
+    }
+
     fn get_barycentric_coordinates(&self, p: Vec3A) -> Vec3A {
         let v1v2 = self.v2.position - self.v1.position;
         let v1v3 = self.v3.position - self.v1.position;
@@ -117,7 +230,7 @@ impl Triangle {
 }
 
 impl Vertex {
-    pub const fn new(position: Vec3A, normal: Vec3A, uv: Option<Vec2>) -> Self {
-        Self { position, normal, uv }
+    pub const fn new(position: Vec3A, normal: Vec3A, uv: Option<Vec2>, tangent: Option<Vec4>) -> Self {
+        Self { position, normal, uv, tangent }
     }
 }
@@ -1,15 +1,13 @@
 use std::{
-    path::PathBuf,
-    fs::File,
+    path::Path,
+    fs::{create_dir_all, File},
     io::BufWriter
 };
 
-static OUT_PATH: &str = "out/";
-
-pub fn save_png(image_name: &str, width: usize, height: usize, pixels: Vec<u32>) {
-    let mut out_path = PathBuf::new();
-    out_path.push(OUT_PATH);
-    out_path.push(format!("{}.png", image_name));
+pub fn save_png(out_path: &Path, width: usize, height: usize, pixels: Vec<u32>) {
+    if let Some(parent) = out_path.parent() {
+        create_dir_all(parent).unwrap();
+    }
 
     let file = File::create(out_path).unwrap();
     let w = BufWriter::new(file);
@@ -1,5 +1,5 @@
 pub mod rand;
-pub use rand::random_unit_vector;
+pub use rand::{random_unit_vector, random_in_unit_disk};
 
 pub mod image;
 pub use image::save_png;
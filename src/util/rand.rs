@@ -11,6 +11,16 @@ pub fn random_unit_vector() -> Vec3A {
     }
 }
 
+// Uniformly distributed point in the unit disk in the xy plane.
+pub fn random_in_unit_disk() -> Vec3A {
+    loop {
+        let p = Vec3A::new(signed_rand(), signed_rand(), 0.0);
+        if p.length_squared() < 1.0 {
+            return p;
+        }
+    }
+}
+
 fn signed_rand() -> f32 {
     f32() * 2.0 - 1.0
 }
@@ -27,3 +27,9 @@ impl Statistics {
         println!("{}\n{}", self.header, self.values);
     }
 }
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}